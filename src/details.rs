@@ -0,0 +1,74 @@
+use anyhow::Result;
+use derive_more::Constructor;
+use futures::stream::BoxStream;
+use par_stream::ParParams;
+use par_stream::ParStreamExt;
+
+use crate::illust::NotUgoira;
+use crate::pixiv::types::IllustDetails;
+use crate::pixiv::types::IllustId;
+use crate::pixiv::types::IllustType;
+use crate::pixiv::CLIENT as PIXIV_CLIENT;
+use crate::util::JobLimit;
+
+/// Details of the illustration, only requested if a later stage needs them
+/// or the type of the work is unknown yet
+#[derive(Constructor)]
+struct IllustDetailsProvider {
+    illust_id: IllustId,
+    required: bool,
+}
+
+impl IllustDetailsProvider {
+    pub async fn into(self) -> Result<Option<IllustDetails>> {
+        if !self.required {
+            return Ok(None);
+        }
+
+        let client = &PIXIV_CLIENT;
+        let details = client.illust_details(self.illust_id).await?;
+        match details.illust_type {
+            IllustType::Ugoira => Ok(Some(details)),
+            illust_type => Err(NotUgoira {
+                illust_id: self.illust_id,
+                illust_type,
+            }
+            .into()),
+        }
+    }
+}
+
+#[derive(Constructor)]
+pub struct StreamIllustDetailsProvider<I>
+where
+    I: 'static,
+{
+    illust_ids: BoxStream<'static, (I, IllustId, bool)>,
+    limit: JobLimit,
+    params: ParParams,
+}
+
+impl<I> StreamIllustDetailsProvider<I>
+where
+    I: Send,
+{
+    pub fn into_stream(self) -> BoxStream<'static, (I, Result<Option<IllustDetails>>)> {
+        let limit = self.limit;
+        Box::pin(self.illust_ids.par_then_unordered(
+            self.params,
+            move |(i, illust_id, required)| {
+                let limit = limit.clone();
+                async move {
+                    // Nothing is requested for illustrations that don't need details
+                    let _permit = match required {
+                        true => Some(limit.acquire().await),
+                        false => None,
+                    };
+                    let prov = IllustDetailsProvider::new(illust_id, required);
+                    let details = prov.into().await;
+                    (i, details)
+                }
+            },
+        ))
+    }
+}
@@ -0,0 +1,31 @@
+use std::fmt;
+use std::process::ExitStatus;
+
+#[derive(Debug)]
+pub enum Error {
+    Ffmpeg {
+        status: ExitStatus,
+        stderr: String,
+        command: String,
+    },
+    EmptyOutput {
+        command: String,
+    },
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Ffmpeg {
+                status,
+                stderr,
+                command,
+            } => write!(f, "ffmpeg failed ({status}): {command}\n{stderr}"),
+            Error::EmptyOutput { command } => {
+                write!(f, "ffmpeg produced no output: {command}")
+            }
+        }
+    }
+}
@@ -0,0 +1,612 @@
+mod error;
+pub mod native;
+
+use std::ffi::OsStr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Output;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use anyhow::Context;
+use anyhow::Result;
+use bytes::Bytes;
+use clap::ArgEnum;
+use derive_more::Constructor;
+use fraction::Fraction;
+use futures::stream::BoxStream;
+use par_stream::ParParams;
+use par_stream::ParStreamExt;
+use tempfile::tempdir;
+use tempfile::NamedTempFile;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+
+pub use self::error::Error;
+use self::native::NativeEncodeParams;
+use crate::pixiv::types::UgoiraDelay;
+use crate::ugoira::UgoiraFrame;
+
+/// How many trailing lines of ffmpeg's stderr are kept in [`Error::Ffmpeg`].
+const STDERR_TAIL_LINES: usize = 20;
+
+pub trait EncodeParams {
+    fn inspect_cmd(&self, cmd: &mut Command);
+
+    /// Whether the frame timestamps can be passed to the muxer as is,
+    /// instead of resampling to the average frame rate
+    fn supports_variable_frame_rate(&self) -> bool {
+        false
+    }
+
+    /// Smallest frame delay step in milliseconds the format can store
+    fn delay_resolution(&self) -> u32 {
+        1
+    }
+
+    /// Output options for writing to a pipe, if they differ from [`Self::inspect_cmd`]
+    fn inspect_pipe_cmd(&self, cmd: &mut Command) {
+        self.inspect_cmd(cmd);
+    }
+
+    /// Whether the muxer seeks back in the output even with [`Self::inspect_pipe_cmd`],
+    /// so it can't be written to a pipe
+    fn requires_seekable_output(&self) -> bool {
+        false
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub enum FrameRate {
+    /// Keep every frame delay, if the format supports it
+    Variable,
+    /// Resample to the average frame rate
+    Average,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub enum FfmpegIo {
+    /// Write frames to a temporary directory and read the output file back
+    TempDir,
+    /// Feed frames through stdin and read the output from stdout.
+    /// APNG and WebP muxers seek back in the output, so it is written to `--spool-dir` instead
+    Pipe,
+}
+
+#[derive(Clone, Copy)]
+pub struct WebmParams;
+#[derive(Clone, Copy)]
+pub struct GifParams;
+#[derive(Clone, Copy)]
+pub struct ApngParams;
+#[derive(Clone, Copy)]
+pub struct Mp4Params;
+#[derive(Clone, Copy)]
+pub struct WebpParams {
+    pub quality: WebpQuality,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum WebpQuality {
+    Lossless,
+    /// Lossy compression with the quality factor in the range of 0 to 100
+    Lossy(u8),
+}
+
+impl EncodeParams for WebmParams {
+    fn inspect_cmd(&self, cmd: &mut Command) {
+        cmd.arg("-c:v");
+        cmd.arg("libvpx-vp9");
+        cmd.arg("-lossless");
+        cmd.arg("1");
+        cmd.arg("-f");
+        cmd.arg("webm");
+    }
+
+    fn supports_variable_frame_rate(&self) -> bool {
+        true
+    }
+}
+
+impl EncodeParams for GifParams {
+    fn inspect_cmd(&self, cmd: &mut Command) {
+        cmd.arg("-vf");
+        cmd.arg("split[s0][s1];[s0]palettegen[p];[s1][p]paletteuse");
+        cmd.arg("-loop");
+        cmd.arg("0");
+        cmd.arg("-f");
+        cmd.arg("gif");
+    }
+
+    fn supports_variable_frame_rate(&self) -> bool {
+        true
+    }
+
+    fn delay_resolution(&self) -> u32 {
+        10
+    }
+}
+
+impl EncodeParams for ApngParams {
+    fn inspect_cmd(&self, cmd: &mut Command) {
+        cmd.arg("-pix_fmt");
+        cmd.arg("rgba");
+        cmd.arg("-plays");
+        cmd.arg("0");
+        cmd.arg("-f");
+        cmd.arg("apng");
+    }
+
+    fn supports_variable_frame_rate(&self) -> bool {
+        true
+    }
+
+    fn requires_seekable_output(&self) -> bool {
+        true
+    }
+}
+
+impl Mp4Params {
+    fn inspect_cmd_with_movflags(&self, cmd: &mut Command, movflags: &str) {
+        cmd.arg("-c:v");
+        cmd.arg("libx264");
+        // yuv420p subsamples chroma, so both dimensions have to be even
+        cmd.arg("-vf");
+        cmd.arg("pad=ceil(iw/2)*2:ceil(ih/2)*2");
+        cmd.arg("-pix_fmt");
+        cmd.arg("yuv420p");
+        cmd.arg("-movflags");
+        cmd.arg(movflags);
+        cmd.arg("-f");
+        cmd.arg("mp4");
+    }
+}
+
+impl EncodeParams for Mp4Params {
+    fn inspect_cmd(&self, cmd: &mut Command) {
+        self.inspect_cmd_with_movflags(cmd, "+faststart");
+    }
+
+    /// Fragmented MP4 is written front to back, at the cost of moving the index
+    /// into the fragments
+    fn inspect_pipe_cmd(&self, cmd: &mut Command) {
+        self.inspect_cmd_with_movflags(cmd, "frag_keyframe+empty_moov");
+    }
+
+    fn supports_variable_frame_rate(&self) -> bool {
+        true
+    }
+}
+
+impl EncodeParams for WebpParams {
+    fn inspect_cmd(&self, cmd: &mut Command) {
+        cmd.arg("-c:v");
+        cmd.arg("libwebp_anim");
+        match self.quality {
+            WebpQuality::Lossless => {
+                cmd.arg("-lossless");
+                cmd.arg("1");
+            }
+            WebpQuality::Lossy(quality) => {
+                cmd.arg("-lossless");
+                cmd.arg("0");
+                cmd.arg("-quality");
+                cmd.arg(format!("{quality}"));
+            }
+        }
+        cmd.arg("-loop");
+        cmd.arg("0");
+        cmd.arg("-f");
+        cmd.arg("webp");
+    }
+
+    fn supports_variable_frame_rate(&self) -> bool {
+        true
+    }
+
+    fn requires_seekable_output(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone)]
+pub enum Backend {
+    Ffmpeg(Arc<dyn EncodeParams + Send + Sync>),
+    Native(Arc<dyn NativeEncodeParams + Send + Sync>),
+}
+
+#[derive(Constructor)]
+struct UgoiraEncoder {
+    data: Vec<UgoiraFrame>,
+    backend: Backend,
+    frame_rate: FrameRate,
+    ffmpeg_io: FfmpegIo,
+    spool_dir: PathBuf,
+}
+
+impl UgoiraEncoder {
+    fn calculate_fps(data: &[UgoiraFrame]) -> Fraction {
+        let num = data.len();
+        let den = data.iter().map(|frame| u32::from(frame.delay)).sum::<u32>();
+        Fraction::from(1000) * Fraction::from(num) / Fraction::from(den)
+    }
+
+    async fn into(self) -> Result<Bytes> {
+        match self.backend {
+            Backend::Ffmpeg(params) => {
+                let args = FfmpegArgs::new(&self.data, params, self.frame_rate);
+                match self.ffmpeg_io {
+                    FfmpegIo::TempDir => Self::into_ffmpeg_temp_dir(&self.data, args).await,
+                    FfmpegIo::Pipe => {
+                        Self::into_ffmpeg_pipe(self.data, args, &self.spool_dir).await
+                    }
+                }
+            }
+            Backend::Native(params) => {
+                let data = self.data;
+                tokio::task::spawn_blocking(move || params.encode(&data)).await?
+            }
+        }
+    }
+
+    async fn into_ffmpeg_temp_dir(data: &[UgoiraFrame], args: FfmpegArgs) -> Result<Bytes> {
+        // TODO: should be a better, non-Command way to do this;
+        //       look into gstreamer or ffmpeg bindings
+
+        let temp_dir = tempdir()?;
+
+        let mut ffconcat = File::create(temp_dir.path().join("ffconcat.txt")).await?;
+        ffconcat.write_all(FFCONCAT_HEADER.as_bytes()).await?;
+
+        for (frame, delay) in data.iter().zip(&args.delays) {
+            ffconcat
+                .write_all(ffconcat_entry(&frame.file, *delay).as_bytes())
+                .await?;
+
+            let mut frame_file = File::create(temp_dir.path().join(&frame.file)).await?;
+            tokio::io::copy(&mut frame.data().await?.as_ref(), &mut frame_file).await?;
+        }
+        if let Some(frame) = data.last() {
+            ffconcat
+                .write_all(ffconcat_last_entry(&frame.file).as_bytes())
+                .await?;
+        }
+
+        ffconcat.sync_all().await?;
+
+        let mut cmd = Command::new("ffmpeg");
+
+        cmd.arg("-y");
+        cmd.arg("-i");
+        cmd.arg("ffconcat.txt");
+        args.inspect_cmd(&mut cmd, false);
+        cmd.arg("out");
+
+        cmd.current_dir(&temp_dir);
+        cmd.stdin(Stdio::null());
+        cmd.stderr(Stdio::piped());
+        cmd.stdout(Stdio::null());
+
+        let command = format_cmd(&cmd);
+
+        let proc = cmd.spawn().context("failed to spawn ffmpeg")?;
+        let output = proc.wait_with_output().await?;
+        check_status(&output, &command)?;
+
+        let mut buf_writer = Vec::<u8>::new();
+        let mut outfile = File::open(temp_dir.path().join("out")).await?;
+        tokio::io::copy(&mut outfile, &mut buf_writer).await?;
+
+        check_output(buf_writer, command)
+    }
+
+    /// Passes the same ffconcat script through stdin, with the frames inlined as `data:` URIs,
+    /// so the frame timing is identical to [`Self::into_ffmpeg_temp_dir`]
+    async fn into_ffmpeg_pipe(
+        data: Vec<UgoiraFrame>,
+        args: FfmpegArgs,
+        spool_dir: &Path,
+    ) -> Result<Bytes> {
+        // Muxers that seek back still need an output file,
+        // kept out of the system temporary directory like downloads
+        let out_file = if args.params.requires_seekable_output() {
+            tokio::fs::create_dir_all(spool_dir).await?;
+            Some(NamedTempFile::new_in(spool_dir)?.into_temp_path())
+        } else {
+            None
+        };
+
+        let mut cmd = Command::new("ffmpeg");
+
+        cmd.arg("-y");
+        cmd.arg("-f");
+        cmd.arg("concat");
+        cmd.arg("-safe");
+        cmd.arg("0");
+        cmd.arg("-protocol_whitelist");
+        cmd.arg("pipe,data");
+        cmd.arg("-i");
+        cmd.arg("pipe:0");
+        args.inspect_cmd(&mut cmd, out_file.is_none());
+        match &out_file {
+            Some(out_file) => cmd.arg(out_file.as_os_str()),
+            None => cmd.arg("pipe:1"),
+        };
+
+        cmd.stdin(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        cmd.stdout(Stdio::piped());
+
+        let command = format_cmd(&cmd);
+
+        let mut proc = cmd.spawn().context("failed to spawn ffmpeg")?;
+
+        // Written from a task of its own, as ffmpeg may block on stdout before reading all input
+        let mut stdin = proc.stdin.take().unwrap();
+        let delays = args.delays;
+        let writer: JoinHandle<Result<()>> = tokio::spawn(async move {
+            stdin.write_all(FFCONCAT_HEADER.as_bytes()).await?;
+            let mut last_uri = None;
+            for (frame, delay) in data.iter().zip(delays) {
+                let mime_type = match Path::new(&frame.file).extension().and_then(OsStr::to_str) {
+                    Some("png") => "image/png",
+                    _ => "image/jpeg",
+                };
+                let uri = format!(
+                    "data:{mime_type};base64,{}",
+                    base64::encode(frame.data().await?)
+                );
+                stdin
+                    .write_all(ffconcat_entry(&uri, delay).as_bytes())
+                    .await?;
+                last_uri = Some(uri);
+            }
+            if let Some(uri) = last_uri {
+                stdin
+                    .write_all(ffconcat_last_entry(&uri).as_bytes())
+                    .await?;
+            }
+            stdin.shutdown().await?;
+            Ok(())
+        });
+
+        let output = proc.wait_with_output().await?;
+        check_status(&output, &command)?;
+        writer.await??;
+
+        let buf_writer = match &out_file {
+            Some(out_file) => tokio::fs::read(out_file).await?,
+            None => output.stdout,
+        };
+
+        check_output(buf_writer, command)
+    }
+}
+
+/// Arguments shared by both ways of running ffmpeg
+struct FfmpegArgs {
+    params: Arc<dyn EncodeParams + Send + Sync>,
+    variable_frame_rate: bool,
+    /// Delays written to the ffconcat script
+    delays: Vec<UgoiraDelay>,
+    fps: Fraction,
+}
+
+impl FfmpegArgs {
+    fn new(
+        data: &[UgoiraFrame],
+        params: Arc<dyn EncodeParams + Send + Sync>,
+        frame_rate: FrameRate,
+    ) -> Self {
+        let variable_frame_rate = match frame_rate {
+            FrameRate::Variable => params.supports_variable_frame_rate(),
+            FrameRate::Average => false,
+        };
+
+        let delays = if variable_frame_rate {
+            quantize_delays(data, params.delay_resolution())
+        } else {
+            data.iter().map(|frame| frame.delay).collect()
+        };
+
+        Self {
+            params,
+            variable_frame_rate,
+            delays,
+            fps: UgoiraEncoder::calculate_fps(data),
+        }
+    }
+
+    /// Output options, everything between the input and the output path
+    fn inspect_cmd(&self, cmd: &mut Command, pipe: bool) {
+        match pipe {
+            true => self.params.inspect_pipe_cmd(cmd),
+            false => self.params.inspect_cmd(cmd),
+        }
+        if self.variable_frame_rate {
+            cmd.arg("-vsync");
+            cmd.arg("vfr");
+        } else {
+            cmd.arg("-r");
+            cmd.arg(format!("{}", self.fps));
+        }
+    }
+}
+
+const FFCONCAT_HEADER: &str = "ffconcat version 1.0\n";
+
+fn ffconcat_entry(file: &str, delay: UgoiraDelay) -> String {
+    let duration = Fraction::from(u32::from(delay)) / Fraction::from(1000);
+    format!("\nfile '{file}'\nduration {duration:.3}\n")
+}
+
+/// The concat demuxer doesn't reliably apply the duration of the last entry,
+/// so the last frame is listed once more to end the one before it
+fn ffconcat_last_entry(file: &str) -> String {
+    format!("\nfile '{file}'\n")
+}
+
+fn check_status(output: &Output, command: &str) -> Result<()> {
+    if !output.status.success() {
+        return Err(Error::Ffmpeg {
+            status: output.status,
+            stderr: stderr_tail(&output.stderr),
+            command: command.to_string(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+fn check_output(buf_writer: Vec<u8>, command: String) -> Result<Bytes> {
+    if buf_writer.is_empty() {
+        return Err(Error::EmptyOutput { command }.into());
+    }
+
+    Ok(Bytes::from(buf_writer))
+}
+
+/// Rounds frame delays to multiples of `resolution` milliseconds.
+///
+/// Rounding is done on the timestamps rather than on every delay by itself,
+/// so the error doesn't accumulate over the animation. Delays never drop below
+/// `resolution`, as players treat zero delays as their own default;
+/// the time added that way is taken from the following frames.
+pub fn quantize_delays(data: &[UgoiraFrame], resolution: u32) -> Vec<UgoiraDelay> {
+    let round = |time: u32| (time + resolution / 2) / resolution * resolution;
+
+    data.iter()
+        .scan((0, 0), |(time, elapsed), frame| {
+            *time += u32::from(frame.delay);
+            let delay = round(*time).saturating_sub(*elapsed).max(resolution);
+            *elapsed += delay;
+            Some(UgoiraDelay::from(delay))
+        })
+        .collect()
+}
+
+fn format_cmd(cmd: &Command) -> String {
+    let cmd = cmd.as_std();
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn stderr_tail(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let lines = stderr.lines().collect::<Vec<_>>();
+    let skip = lines.len().saturating_sub(STDERR_TAIL_LINES);
+    lines[skip..].join("\n")
+}
+
+#[derive(Constructor)]
+pub struct StreamUgoiraEncoder<I>
+where
+    I: 'static,
+{
+    data_stream: BoxStream<'static, (I, Vec<UgoiraFrame>)>,
+    backend: Backend,
+    frame_rate: FrameRate,
+    ffmpeg_io: FfmpegIo,
+    /// Where outputs that can't be piped are written in [`FfmpegIo::Pipe`] mode
+    spool_dir: PathBuf,
+    params: ParParams,
+}
+
+impl<I> StreamUgoiraEncoder<I>
+where
+    I: Send,
+{
+    pub fn into_stream(self) -> BoxStream<'static, (I, Result<Bytes>)> {
+        let backend = self.backend;
+        let frame_rate = self.frame_rate;
+        let ffmpeg_io = self.ffmpeg_io;
+        let spool_dir = self.spool_dir;
+        Box::pin(
+            self.data_stream
+                .par_then_unordered(self.params, move |(i, data)| {
+                    let encoder = UgoiraEncoder::new(
+                        data,
+                        backend.clone(),
+                        frame_rate,
+                        ffmpeg_io,
+                        spool_dir.clone(),
+                    );
+                    async move {
+                        let encode = encoder.into().await;
+                        (i, encode)
+                    }
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::quantize_delays;
+    use crate::pixiv::types::UgoiraDelay;
+    use crate::ugoira::UgoiraFrame;
+
+    fn quantize(delays: &[u32], resolution: u32) -> Vec<u32> {
+        let frames = delays
+            .iter()
+            .enumerate()
+            .map(|(n, &delay)| {
+                UgoiraFrame::repaired(
+                    format!("{n:06}.jpg"),
+                    UgoiraDelay::from(delay),
+                    Bytes::new(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        quantize_delays(&frames, resolution)
+            .into_iter()
+            .map(u32::from)
+            .collect()
+    }
+
+    #[test]
+    fn keeps_multiples_of_resolution() {
+        let delays = [10, 20, 100, 30, 10];
+        assert_eq!(quantize(&delays, 10), delays);
+        assert_eq!(quantize(&delays, 1), delays);
+    }
+
+    #[test]
+    fn preserves_total_duration() {
+        for delays in [
+            &[33, 33, 34, 33, 33, 34][..],
+            &[15, 15, 15, 15],
+            &[4, 16, 27, 83],
+            &[125, 125, 40, 60],
+        ] {
+            let quantized = quantize(delays, 10);
+            let total = delays.iter().sum::<u32>();
+            assert_eq!(
+                quantized.iter().sum::<u32>(),
+                (total + 5) / 10 * 10,
+                "{delays:?}"
+            );
+            assert!(
+                quantized.iter().all(|delay| delay % 10 == 0),
+                "{quantized:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn never_emits_zero_delays() {
+        assert_eq!(quantize(&[4, 16], 10), [10, 10]);
+        assert_eq!(quantize(&[4, 4, 4, 28], 10), [10, 10, 10, 10]);
+        assert_eq!(quantize(&[1, 1, 1], 10), [10, 10, 10]);
+        assert!(quantize(&[3; 50], 10).iter().all(|&delay| delay >= 10));
+    }
+}
@@ -0,0 +1,171 @@
+use std::io::Cursor;
+
+use anyhow::bail;
+use anyhow::Result;
+use bytes::Bytes;
+use clap::ArgEnum;
+use color_quant::NeuQuant;
+use gif::Encoder;
+use gif::Frame;
+use gif::Repeat;
+use image::io::Reader as ImageReader;
+use image::RgbaImage;
+
+use super::quantize_delays;
+use crate::pixiv::types::UgoiraDelay;
+use crate::ugoira::UgoiraFrame;
+
+/// Sampling factor for NeuQuant, 1 being the slowest and the most accurate.
+const NEUQUANT_SAMPLE_FACTOR: i32 = 1;
+/// Upper bound of pixels a global palette is trained on.
+const PALETTE_SAMPLE_PIXELS: usize = 1 << 22;
+
+pub trait NativeEncodeParams {
+    fn encode(&self, data: &[UgoiraFrame]) -> Result<Bytes>;
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub enum GifPalette {
+    /// One palette trained on all of the frames, like ffmpeg's `palettegen`
+    Global,
+    /// Every frame gets a palette of its own
+    PerFrame,
+}
+
+#[derive(Clone, Copy)]
+pub struct GifParams {
+    pub palette: GifPalette,
+}
+#[derive(Clone, Copy)]
+pub struct ApngParams;
+
+impl NativeEncodeParams for GifParams {
+    fn encode(&self, data: &[UgoiraFrame]) -> Result<Bytes> {
+        let dimensions = frame_dimensions(data)?;
+        let width = u16::try_from(dimensions.0)?;
+        let height = u16::try_from(dimensions.1)?;
+
+        let global_palette = match self.palette {
+            GifPalette::Global => Some(train_palette(data, dimensions)?),
+            GifPalette::PerFrame => None,
+        };
+
+        let mut buf_writer = Vec::<u8>::new();
+        {
+            let palette = global_palette
+                .as_ref()
+                .map(NeuQuant::color_map_rgb)
+                .unwrap_or_default();
+            let mut encoder = Encoder::new(&mut buf_writer, width, height, &palette)?;
+            encoder.set_repeat(Repeat::Infinite)?;
+
+            let delays = quantize_delays(data, 10);
+            for (frame, delay) in data.iter().zip(delays) {
+                let image = decode_frame(frame, dimensions)?;
+                let mut frame = match &global_palette {
+                    Some(nq) => {
+                        let pixels = image
+                            .pixels()
+                            .map(|pixel| nq.index_of(&pixel.0) as u8)
+                            .collect::<Vec<_>>();
+                        Frame::from_indexed_pixels(width, height, &pixels, None)
+                    }
+                    None => {
+                        let mut pixels = image.into_raw();
+                        Frame::from_rgba_speed(width, height, &mut pixels, NEUQUANT_SAMPLE_FACTOR)
+                    }
+                };
+                frame.delay = u16::try_from(u32::from(delay) / 10)?;
+                encoder.write_frame(&frame)?;
+            }
+        }
+
+        Ok(Bytes::from(buf_writer))
+    }
+}
+
+impl NativeEncodeParams for ApngParams {
+    fn encode(&self, data: &[UgoiraFrame]) -> Result<Bytes> {
+        let dimensions = frame_dimensions(data)?;
+        let (width, height) = dimensions;
+
+        let mut buf_writer = Vec::<u8>::new();
+        {
+            let mut encoder = png::Encoder::new(&mut buf_writer, width, height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_animated(u32::try_from(data.len())?, 0)?;
+
+            let mut writer = encoder.write_header()?;
+            for frame in data {
+                let image = decode_frame(frame, dimensions)?;
+                let (num, den) = apng_delay(frame.delay)?;
+                writer.set_frame_delay(num, den)?;
+                writer.write_image_data(&image)?;
+            }
+            writer.finish()?;
+        }
+
+        Ok(Bytes::from(buf_writer))
+    }
+}
+
+/// Converts delay to the `delay_num / delay_den` seconds fraction of an APNG frame
+fn apng_delay(delay: UgoiraDelay) -> Result<(u16, u16)> {
+    fn gcd(a: u32, b: u32) -> u32 {
+        if b == 0 {
+            a
+        } else {
+            gcd(b, a % b)
+        }
+    }
+
+    let num = u32::from(delay);
+    let den = 1000;
+    let div = gcd(num, den).max(1);
+
+    match u16::try_from(num / div) {
+        Ok(num) => Ok((num, u16::try_from(den / div)?)),
+        Err(_) => bail!("frame delay {delay}ms is too long for APNG"),
+    }
+}
+
+/// Dimensions of the first frame, read from its header, that every frame is expected to have
+fn frame_dimensions(data: &[UgoiraFrame]) -> Result<(u32, u32)> {
+    let frame = match data.first() {
+        Some(frame) => frame,
+        None => bail!("ugoira has no frames"),
+    };
+
+    let reader = ImageReader::new(Cursor::new(frame.blocking_data()?)).with_guessed_format()?;
+    Ok(reader.into_dimensions()?)
+}
+
+/// Decodes the frame, checking its dimensions. Encoders go over the frames one at a time,
+/// the global palette taking a pass of its own, so memory use doesn't grow with the length
+fn decode_frame(frame: &UgoiraFrame, (width, height): (u32, u32)) -> Result<RgbaImage> {
+    let image = image::load_from_memory(&frame.blocking_data()?)?.into_rgba8();
+    if image.dimensions() != (width, height) {
+        bail!(
+            "frame {} is {}x{}, expected {width}x{height}",
+            frame.file,
+            image.width(),
+            image.height(),
+        );
+    }
+
+    Ok(image)
+}
+
+fn train_palette(data: &[UgoiraFrame], (width, height): (u32, u32)) -> Result<NeuQuant> {
+    let total = data.len() * width as usize * height as usize;
+    let step = (total / PALETTE_SAMPLE_PIXELS).max(1);
+
+    let mut samples = Vec::new();
+    for frame in data {
+        let image = decode_frame(frame, (width, height))?;
+        samples.extend(image.pixels().step_by(step).flat_map(|pixel| pixel.0));
+    }
+
+    Ok(NeuQuant::new(NEUQUANT_SAMPLE_FACTOR, 256, &samples))
+}
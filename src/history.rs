@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::RwLock;
+
+use anyhow::Result;
+use chrono::DateTime;
+use chrono::FixedOffset;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::fs::File;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::pixiv::types::IllustDetails;
+use crate::pixiv::types::IllustId;
+use crate::pixiv::types::ProfileId;
+use crate::save::ExistingPolicy;
+use crate::save::SavedFile;
+
+lazy_static! {
+    pub static ref HISTORY: History = History::default();
+}
+
+/// Name of the history file in the output directory
+pub const HISTORY_FILE: &str = ".ugokuna-history.jsonl";
+
+/// Outcome of processing an illustration, one JSON line in the history file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Record {
+    pub illust_id: IllustId,
+    /// Known if the input listed the illustration along with its user,
+    /// or the illustration details were fetched
+    pub user_id: Option<ProfileId>,
+    /// Zip the frames were downloaded from
+    pub url: Option<String>,
+    /// `--format`, or `zip` when only raw files are saved, e.g. `gif+zip`
+    pub format: String,
+    pub files: Vec<SavedFile>,
+    pub timestamp: DateTime<Utc>,
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Record {
+    /// Whether the record passes the filters of the `history` command
+    pub fn matches(&self, failed: bool, user_id: Option<ProfileId>) -> bool {
+        if failed && self.success {
+            return false;
+        }
+        user_id.is_none() || self.user_id == user_id
+    }
+}
+
+/// Newest work of the follow-latest feed that a run has paged to,
+/// one JSON line in the history file between the records
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct FeedMark {
+    follow_latest: IllustId,
+    timestamp: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Record(Record),
+    FeedMark(FeedMark),
+}
+
+/// Append-only store of records, keeping the latest one of every illustration at hand
+#[derive(Default)]
+pub struct History {
+    latest: RwLock<HashMap<IllustId, Record>>,
+    /// Where the follow-latest feed was paged to by earlier runs
+    follow_latest: RwLock<Option<IllustId>>,
+    /// Works of the feed seen by this run, the mark being recorded once it's over
+    follow_latest_seen: RwLock<Vec<IllustId>>,
+    /// Illustrations that failed in this run
+    failures: RwLock<HashSet<IllustId>>,
+    /// Users of the illustrations, as listed by the inputs of this run
+    users: RwLock<HashMap<IllustId, ProfileId>>,
+    file: Mutex<Option<File>>,
+    format: RwLock<String>,
+}
+
+impl History {
+    /// Loads the records from `path` and appends the new ones to it,
+    /// stating `format` in every one of them
+    pub async fn open(&self, path: &Path, format: impl Into<String>) -> Result<()> {
+        let lines = read_lines(path).await?;
+        {
+            let mut latest = self.latest.write().unwrap();
+            let mut follow_latest = self.follow_latest.write().unwrap();
+            for line in lines {
+                match line {
+                    Line::Record(record) => {
+                        latest.insert(record.illust_id, record);
+                    }
+                    Line::FeedMark(mark) => *follow_latest = Some(mark.follow_latest),
+                }
+            }
+        }
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        *self.file.lock().await = Some(file);
+        *self.format.write().unwrap() = format.into();
+
+        Ok(())
+    }
+
+    /// Whether the illustration was saved in the current format by an earlier run,
+    /// and is up to date according to the policy
+    pub fn is_saved(
+        &self,
+        illust_id: IllustId,
+        policy: ExistingPolicy,
+        updated: Option<DateTime<FixedOffset>>,
+    ) -> bool {
+        let latest = self.latest.read().unwrap();
+        let format = self.format.read().unwrap();
+
+        let record = match latest.get(&illust_id) {
+            Some(record) if record.success && record.format == *format => record,
+            _ => return false,
+        };
+
+        match (policy, updated) {
+            (ExistingPolicy::Overwrite, _) => false,
+            (ExistingPolicy::Skip, _) | (ExistingPolicy::UpdateIfChanged, None) => true,
+            (ExistingPolicy::UpdateIfChanged, Some(updated)) => record.timestamp >= updated,
+        }
+    }
+
+    /// Notes the user an input listed the illustration with,
+    /// so that its records tell even if the details aren't fetched
+    pub fn listed(&self, illust_id: IllustId, user_id: ProfileId) {
+        self.users.write().unwrap().insert(illust_id, user_id);
+    }
+
+    /// Newest work of the follow-latest feed that an earlier run has seen
+    pub fn follow_latest(&self) -> Option<IllustId> {
+        *self.follow_latest.read().unwrap()
+    }
+
+    /// Notes the works of the feed, to be marked by [`Self::finish_run`]
+    pub fn saw_follow_latest(&self, illust_ids: impl IntoIterator<Item = IllustId>) {
+        let mut seen = self.follow_latest_seen.write().unwrap();
+        seen.extend(illust_ids);
+    }
+
+    /// Records what the run has seen of the follow-latest feed,
+    /// after every illustration queued from it has been processed
+    pub async fn finish_run(&self) {
+        let illust_id = {
+            let seen = self.follow_latest_seen.read().unwrap();
+            let failures = self.failures.read().unwrap();
+
+            // The mark stays below the oldest work that failed, so that the next run retries it
+            let oldest_failed = seen
+                .iter()
+                .filter(|id| failures.contains(id))
+                .map(|id| u32::from(*id))
+                .min();
+            let newest = seen
+                .iter()
+                .filter(|id| match oldest_failed {
+                    Some(failed) => u32::from(**id) < failed,
+                    None => true,
+                })
+                .max_by_key(|id| u32::from(**id));
+            match newest {
+                Some(illust_id) => *illust_id,
+                None => return,
+            }
+        };
+
+        let mark = FeedMark {
+            follow_latest: illust_id,
+            timestamp: Utc::now(),
+        };
+        if let Err(e) = self.append(&mark).await {
+            eprintln!("Error while writing follow-latest history: {e:?}");
+        }
+    }
+
+    pub async fn finished(
+        &self,
+        illust_id: IllustId,
+        details: Option<&IllustDetails>,
+        url: Option<String>,
+        files: Vec<SavedFile>,
+    ) {
+        let mut record = self.new_record(illust_id, details);
+        record.url = url;
+        record.files = files;
+        record.success = true;
+        self.record(record).await;
+    }
+
+    pub async fn failed(
+        &self,
+        illust_id: IllustId,
+        details: Option<&IllustDetails>,
+        url: Option<String>,
+        error: &anyhow::Error,
+    ) {
+        let mut record = self.new_record(illust_id, details);
+        record.url = url;
+        record.error = Some(format!("{error:#}"));
+        self.record(record).await;
+    }
+
+    fn new_record(&self, illust_id: IllustId, details: Option<&IllustDetails>) -> Record {
+        Record {
+            illust_id,
+            user_id: details
+                .map(|d| d.user_id)
+                .or_else(|| self.users.read().unwrap().get(&illust_id).copied()),
+            url: None,
+            format: self.format.read().unwrap().clone(),
+            files: Vec::new(),
+            timestamp: Utc::now(),
+            success: false,
+            error: None,
+        }
+    }
+
+    /// Failing to keep the history doesn't fail the illustration, so errors are only reported
+    async fn record(&self, record: Record) {
+        if !record.success {
+            self.failures.write().unwrap().insert(record.illust_id);
+        }
+        if let Err(e) = self.append(&record).await {
+            eprintln!(
+                "Error while writing illustration {} history: {e:?}",
+                record.illust_id
+            );
+        }
+
+        let mut latest = self.latest.write().unwrap();
+        latest.insert(record.illust_id, record);
+    }
+
+    async fn append(&self, line: &impl Serialize) -> Result<()> {
+        let mut line = serde_json::to_vec(line)?;
+        line.push(b'\n');
+
+        if let Some(file) = self.file.lock().await.as_mut() {
+            file.write_all(&line).await?;
+            file.flush().await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads every record of the history file, in the order they were written
+pub async fn read_records(path: &Path) -> Result<Vec<Record>> {
+    let lines = read_lines(path).await?;
+    let records = lines
+        .into_iter()
+        .filter_map(|line| match line {
+            Line::Record(record) => Some(record),
+            Line::FeedMark(_) => None,
+        })
+        .collect();
+
+    Ok(records)
+}
+
+async fn read_lines(path: &Path) -> Result<Vec<Line>> {
+    let data = match tokio::fs::read_to_string(path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut lines = Vec::new();
+    for (n, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // A line cut short by an interrupted run shouldn't make the history unusable
+        match serde_json::from_str(line) {
+            Ok(line) => lines.push(line),
+            Err(e) => eprintln!("Skipping line {} of {path:?}: {e}", n + 1),
+        }
+    }
+
+    Ok(lines)
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let timestamp = self.timestamp.format("%Y-%m-%d %H:%M:%S");
+        let status = if self.success { "ok" } else { "failed" };
+        let user_id = self
+            .user_id
+            .map_or_else(|| "-".to_string(), |id| id.to_string());
+
+        write!(
+            f,
+            "{timestamp} {} {status} user {user_id} {}",
+            self.illust_id, self.format
+        )?;
+        match &self.error {
+            Some(error) => write!(f, ": {error}"),
+            None => {
+                for file in &self.files {
+                    write!(f, " {}", file.path.display())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn records_user_listed_by_the_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(HISTORY_FILE);
+        let history = History::default();
+        history.open(&path, "gif").await.unwrap();
+
+        let error = anyhow::anyhow!("frames are missing");
+        history.listed(IllustId::from(111), ProfileId::from(678));
+        history
+            .failed(IllustId::from(111), None, None, &error)
+            .await;
+        history
+            .failed(IllustId::from(222), None, None, &error)
+            .await;
+
+        let records = read_records(&path).await.unwrap();
+        let matched = records
+            .iter()
+            .filter(|record| record.matches(true, Some(ProfileId::from(678))))
+            .map(|record| record.illust_id)
+            .collect::<Vec<_>>();
+        assert_eq!(matched, [IllustId::from(111)]);
+        assert!(records[1].matches(true, None));
+    }
+
+    #[tokio::test]
+    async fn marks_the_feed_below_failed_works() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(HISTORY_FILE);
+        let history = History::default();
+        history.open(&path, "gif").await.unwrap();
+
+        let error = anyhow::anyhow!("frames are missing");
+        history.saw_follow_latest([5, 4, 3, 2].map(IllustId::from));
+        history
+            .finished(IllustId::from(5), None, None, Vec::new())
+            .await;
+        history.failed(IllustId::from(4), None, None, &error).await;
+        history.failed(IllustId::from(3), None, None, &error).await;
+        history
+            .finished(IllustId::from(2), None, None, Vec::new())
+            .await;
+        history.finish_run().await;
+
+        let reopened = History::default();
+        reopened.open(&path, "gif").await.unwrap();
+        assert_eq!(reopened.follow_latest(), Some(IllustId::from(2)));
+    }
+}
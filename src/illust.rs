@@ -1,15 +1,84 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::num::NonZeroU32;
+
+use anyhow::anyhow;
 use anyhow::Result;
 use async_stream::stream;
 use async_stream::try_stream;
+use chrono::NaiveDate;
 use derive_more::Constructor;
 use futures::stream::BoxStream;
+use reqwest::Url;
 
+use crate::history::HISTORY;
+use crate::input::Input;
+use crate::pixiv::types::BookmarkRest;
 use crate::pixiv::types::IllustId;
+use crate::pixiv::types::IllustSummary;
+use crate::pixiv::types::IllustThumbnail;
+use crate::pixiv::types::IllustType;
 use crate::pixiv::types::ProfileId;
+use crate::pixiv::types::SearchQuery;
 use crate::pixiv::CLIENT as PIXIV_CLIENT;
+use crate::pixiv::PROFILE_ILLUSTS_BATCH;
+
+/// Work that was skipped because there is nothing to animate
+#[derive(Debug)]
+pub struct NotUgoira {
+    pub illust_id: IllustId,
+    pub illust_type: IllustType,
+}
+
+impl std::error::Error for NotUgoira {}
+
+impl fmt::Display for NotUgoira {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "illustration {} is {:?}, not ugoira",
+            self.illust_id, self.illust_type
+        )
+    }
+}
+
+/// Work that was listed, but whose details pixiv didn't send, e.g. deleted or private
+#[derive(Debug)]
+pub struct Unavailable {
+    pub illust_id: IllustId,
+}
+
+impl std::error::Error for Unavailable {}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "illustration {} is unavailable", self.illust_id)
+    }
+}
+
+/// Illustration id provided by one of the inputs
+#[derive(Clone, Copy, Debug)]
+pub struct QueuedIllust {
+    pub illust_id: IllustId,
+    /// Whether the provider has made sure that the work is ugoira
+    pub checked: bool,
+    /// Author of the work, if the provider's listing tells
+    pub user_id: Option<ProfileId>,
+}
+
+impl QueuedIllust {
+    /// Ugoira of the given user, as listed by pixiv
+    fn listed(illust_id: IllustId, user_id: ProfileId) -> Self {
+        Self {
+            illust_id,
+            checked: true,
+            user_id: Some(user_id),
+        }
+    }
+}
 
 pub trait IllustIdProvider {
-    fn into_stream(self) -> BoxStream<'static, Result<IllustId>>;
+    fn into_stream(self) -> BoxStream<'static, Result<QueuedIllust>>;
 }
 
 #[derive(Constructor)]
@@ -22,63 +91,265 @@ struct ProfileIllustIdProvider {
     profile_id: ProfileId,
 }
 
+#[derive(Constructor)]
+struct BookmarksIllustIdProvider {
+    profile_id: ProfileId,
+    rest: BookmarkRest,
+    tag: Option<String>,
+}
+
+/// New works of followed users, paged back to the date if given,
+/// otherwise to the newest one an earlier run has seen
+#[derive(Constructor)]
+struct FollowLatestIllustIdProvider {
+    until: Option<NaiveDate>,
+}
+
+/// Ugoira matching the search query, up to the given number of pages
+#[derive(Constructor)]
+struct SearchIllustIdProvider {
+    query: SearchQuery,
+    max_pages: Option<NonZeroU32>,
+}
+
+/// Resolves a `pixiv.me` link, then provides what it leads to
+#[derive(Constructor)]
+struct ShortLinkIllustIdProvider {
+    url: Url,
+}
+
 #[derive(Constructor)]
 pub struct MasterIllustIdProvider {
-    illust_ids: Vec<IllustId>,
-    profile_ids: Vec<ProfileId>,
+    inputs: Vec<Input>,
 }
 
 impl IllustIdProvider for SimpleIllustIdProvider {
-    fn into_stream(self) -> BoxStream<'static, Result<IllustId>> {
+    fn into_stream(self) -> BoxStream<'static, Result<QueuedIllust>> {
+        // Only ids given directly are queued without knowing their type
         Box::pin(try_stream! {
-            yield self.illust_id;
+            yield QueuedIllust {
+                illust_id: self.illust_id,
+                checked: false,
+                user_id: None,
+            };
         })
     }
 }
 
 impl IllustIdProvider for ProfileIllustIdProvider {
-    fn into_stream(self) -> BoxStream<'static, Result<IllustId>> {
+    fn into_stream(self) -> BoxStream<'static, Result<QueuedIllust>> {
+        Box::pin(stream! {
+            let client = &PIXIV_CLIENT;
+            let mut illusts = match client.profile(self.profile_id).await {
+                Ok(resp) => resp.illusts,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+            illusts.sort_unstable_by_key(|illust_id| std::cmp::Reverse(u32::from(*illust_id)));
+
+            for illust_ids in illusts.chunks(PROFILE_ILLUSTS_BATCH) {
+                let works = match client.profile_illusts(self.profile_id, illust_ids).await {
+                    Ok(resp) => resp.works,
+                    Err(e) => {
+                        yield Err(e);
+                        continue;
+                    }
+                };
+
+                for illust_id in illust_ids {
+                    if !works.iter().any(|work| work.id == *illust_id) {
+                        yield Err(Unavailable { illust_id: *illust_id }.into());
+                    }
+                }
+
+                for IllustSummary { id, illust_type, .. } in works {
+                    match illust_type {
+                        IllustType::Ugoira => yield Ok(QueuedIllust::listed(id, self.profile_id)),
+                        illust_type => yield Err(NotUgoira { illust_id: id, illust_type }.into()),
+                    }
+                }
+            }
+        })
+    }
+}
+
+impl IllustIdProvider for BookmarksIllustIdProvider {
+    fn into_stream(self) -> BoxStream<'static, Result<QueuedIllust>> {
         Box::pin(stream! {
             let client = &PIXIV_CLIENT;
-            let resp = client.profile(self.profile_id).await?;
-
-            let master_stream = futures::stream::select_all(
-                resp.illusts
-                    .into_iter()
-                    .map(IllustId::from)
-                    .map(SimpleIllustIdProvider::new)
-                    .map(IllustIdProvider::into_stream)
-            );
-
-            for await illust_id in master_stream {
-                yield illust_id;
+            let tag = self.tag.as_deref();
+
+            let mut offset = 0;
+            loop {
+                let bookmarks = match client.bookmarks(self.profile_id, self.rest, tag, offset).await {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+                if bookmarks.works.is_empty() {
+                    break;
+                }
+                offset += bookmarks.works.len();
+
+                for IllustSummary { id, illust_type, user_id } in bookmarks.works {
+                    match illust_type {
+                        IllustType::Ugoira => yield Ok(QueuedIllust::listed(id, user_id)),
+                        illust_type => yield Err(NotUgoira { illust_id: id, illust_type }.into()),
+                    }
+                }
+
+                if offset >= bookmarks.total as usize {
+                    break;
+                }
             }
         })
     }
 }
 
-impl IllustIdProvider for MasterIllustIdProvider {
-    fn into_stream(self) -> BoxStream<'static, Result<IllustId>> {
+impl IllustIdProvider for FollowLatestIllustIdProvider {
+    fn into_stream(self) -> BoxStream<'static, Result<QueuedIllust>> {
         Box::pin(stream! {
-            let illusts_stream = futures::stream::select_all(
-                self.illust_ids
-                    .into_iter()
-                    .map(SimpleIllustIdProvider::new)
-                    .map(IllustIdProvider::into_stream),
-            );
-
-            let profiles_stream = futures::stream::select_all(
-                self.profile_ids
-                    .into_iter()
-                    .map(ProfileIllustIdProvider::new)
-                    .map(IllustIdProvider::into_stream),
-            );
-
-            let master_stream = futures::stream::select_all([illusts_stream, profiles_stream]);
-
-            for await illust_id in master_stream {
-                yield illust_id;
+            let client = &PIXIV_CLIENT;
+            let seen_earlier = HISTORY.follow_latest();
+            // Works posted while paging shift the pages, repeating some of them
+            let mut seen = HashSet::new();
+
+            'pages: for page in 1.. {
+                let follow_latest = match client.follow_latest(page).await {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+                if follow_latest.thumbnails.illust.is_empty() {
+                    break;
+                }
+
+                for IllustThumbnail { id, illust_type, create_date, user_id } in follow_latest.thumbnails.illust {
+                    if !seen.insert(id) {
+                        continue;
+                    }
+                    // Ids only grow, so a deleted work doesn't make it page past the mark
+                    let reached = match (self.until, seen_earlier) {
+                        (Some(until), _) => create_date.date_naive() < until,
+                        (None, Some(seen_earlier)) => u32::from(id) <= u32::from(seen_earlier),
+                        (None, None) => false,
+                    };
+                    if reached {
+                        break 'pages;
+                    }
+
+                    match illust_type {
+                        IllustType::Ugoira => yield Ok(QueuedIllust::listed(id, user_id)),
+                        illust_type => yield Err(NotUgoira { illust_id: id, illust_type }.into()),
+                    }
+                }
+
+                if follow_latest.page.is_last_page {
+                    break;
+                }
             }
+
+            // Only a feed paged without errors is marked, so that nothing is missed next time
+            HISTORY.saw_follow_latest(seen);
         })
     }
 }
+
+impl IllustIdProvider for SearchIllustIdProvider {
+    fn into_stream(self) -> BoxStream<'static, Result<QueuedIllust>> {
+        Box::pin(stream! {
+            let client = &PIXIV_CLIENT;
+            let max_pages = self.max_pages.map_or(u32::MAX, NonZeroU32::get);
+
+            for page in 1..=max_pages {
+                let results = match client.search_ugoira(&self.query, page).await {
+                    Ok(resp) => resp.illust,
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+                if results.data.is_empty() {
+                    break;
+                }
+
+                for IllustThumbnail { id, illust_type, user_id, .. } in results.data {
+                    match illust_type {
+                        IllustType::Ugoira => yield Ok(QueuedIllust::listed(id, user_id)),
+                        illust_type => yield Err(NotUgoira { illust_id: id, illust_type }.into()),
+                    }
+                }
+
+                if page >= results.last_page {
+                    break;
+                }
+            }
+        })
+    }
+}
+
+impl IllustIdProvider for ShortLinkIllustIdProvider {
+    fn into_stream(self) -> BoxStream<'static, Result<QueuedIllust>> {
+        Box::pin(stream! {
+            let client = &PIXIV_CLIENT;
+            let input = match client.resolve_redirect(&self.url).await {
+                Ok(url) => match Input::from_url(&url) {
+                    Some(Input::ShortLink(_)) | None => {
+                        yield Err(anyhow!("{} leads to {url}, not to a pixiv user", self.url));
+                        return;
+                    }
+                    Some(input) => input,
+                },
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+
+            for await illust in input_stream(input) {
+                yield illust;
+            }
+        })
+    }
+}
+
+impl MasterIllustIdProvider {
+    pub fn into_stream(self) -> BoxStream<'static, Result<QueuedIllust>> {
+        Box::pin(stream! {
+            let master_stream = futures::stream::select_all(self.inputs.into_iter().map(input_stream));
+
+            // Inputs may overlap, and an illustration queued twice would be downloaded
+            // into the same spool file by both
+            let mut queued = HashSet::new();
+            for await illust in master_stream {
+                if let Ok(QueuedIllust { illust_id, .. }) = &illust {
+                    if !queued.insert(*illust_id) {
+                        continue;
+                    }
+                }
+                yield illust;
+            }
+        })
+    }
+}
+
+fn input_stream(input: Input) -> BoxStream<'static, Result<QueuedIllust>> {
+    match input {
+        Input::Illust(illust_id) => SimpleIllustIdProvider::new(illust_id).into_stream(),
+        Input::Profile(profile_id) => ProfileIllustIdProvider::new(profile_id).into_stream(),
+        Input::Bookmarks { user_id, rest, tag } => {
+            BookmarksIllustIdProvider::new(user_id, rest, tag).into_stream()
+        }
+        Input::FollowLatest { until } => FollowLatestIllustIdProvider::new(until).into_stream(),
+        Input::Search { query, max_pages } => {
+            SearchIllustIdProvider::new(query, max_pages).into_stream()
+        }
+        Input::ShortLink(url) => ShortLinkIllustIdProvider::new(url).into_stream(),
+    }
+}
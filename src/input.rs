@@ -0,0 +1,380 @@
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use chrono::NaiveDate;
+use reqwest::Url;
+use tokio::io::AsyncReadExt;
+
+use crate::pixiv::types::BookmarkRest;
+use crate::pixiv::types::IllustId;
+use crate::pixiv::types::ProfileId;
+use crate::pixiv::types::SearchMode;
+use crate::pixiv::types::SearchOrder;
+use crate::pixiv::types::SearchQuery;
+
+/// Illustration or user, given by id or pixiv URL
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum Input {
+    Illust(IllustId),
+    Profile(ProfileId),
+    /// Bookmarks of the user, only those with the bookmark tag if given
+    Bookmarks {
+        user_id: ProfileId,
+        rest: BookmarkRest,
+        tag: Option<String>,
+    },
+    /// New works of followed users, back to the date or to the newest one seen before
+    FollowLatest {
+        until: Option<NaiveDate>,
+    },
+    /// Ugoira found by tag search, up to the given number of pages
+    Search {
+        query: SearchQuery,
+        max_pages: Option<NonZeroU32>,
+    },
+    /// `pixiv.me/{name}` link, which only redirects to the user page
+    ShortLink(Url),
+}
+
+impl FromStr for Input {
+    type Err = anyhow::Error;
+
+    /// Parses bare illustration ids, and URLs such as `pixiv.net/artworks/{id}`,
+    /// `pixiv.net/users/{id}`, `pixiv.net/users/{id}/bookmarks/artworks`,
+    /// `pixiv.net/tags/{word}`, `member_illust.php?illust_id={id}`, ugoira zips on
+    /// `i.pximg.net` and `pixiv.me/{name}`, with or without the scheme
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(id) = s.parse::<u32>() {
+            return Ok(Input::Illust(IllustId::from(id)));
+        }
+
+        let url = match s.contains("://") {
+            true => Url::parse(s),
+            false => Url::parse(&format!("https://{s}")),
+        };
+
+        match url.ok().as_ref().and_then(Input::from_url) {
+            Some(input) => Ok(input),
+            None => bail!("{s:?} is neither an illustration id nor a pixiv URL"),
+        }
+    }
+}
+
+impl Input {
+    /// Whether the input is only available to a logged in user
+    pub fn requires_login(&self) -> bool {
+        match self {
+            Input::Bookmarks { rest, .. } => *rest == BookmarkRest::Hide,
+            Input::FollowLatest { .. } => true,
+            Input::Search { query, .. } => query.mode == SearchMode::R18,
+            Input::Illust(_) | Input::Profile(_) | Input::ShortLink(_) => false,
+        }
+    }
+
+    pub fn from_url(url: &Url) -> Option<Self> {
+        let segments = url
+            .path_segments()?
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>();
+
+        match url.host_str()? {
+            "www.pixiv.net" | "pixiv.net" => {
+                // Localized pages start with the language, e.g. `/en/artworks/{id}`
+                let segments = match segments.as_slice() {
+                    ["en" | "ko" | "zh" | "zh-tw" | "ja", rest @ ..] => rest,
+                    segments => segments,
+                };
+
+                match segments {
+                    ["artworks", id, ..] | ["i", id] => Some(Input::Illust(parse_id(id)?)),
+                    // e.g. `/users/{id}/bookmarks/artworks/{tag}?rest=hide`
+                    ["users", id, "bookmarks", "artworks", tag @ ..] => {
+                        let rest = match query(url, "rest").as_deref() {
+                            Some("hide") => BookmarkRest::Hide,
+                            _ => BookmarkRest::Show,
+                        };
+                        let tag = match tag {
+                            [tag] => Some(decode(tag)?),
+                            _ => None,
+                        };
+                        Some(Input::Bookmarks {
+                            user_id: parse_id(id)?,
+                            rest,
+                            tag,
+                        })
+                    }
+                    ["users", id, ..] => Some(Input::Profile(parse_id(id)?)),
+                    // e.g. `/tags/{word}/illustrations?type=ugoira&order=date&mode=safe`
+                    ["tags", word, ..] => Some(Input::Search {
+                        query: SearchQuery {
+                            word: decode(word)?,
+                            order: query(url, "order")
+                                .and_then(|order| SearchOrder::from_query(&order))
+                                .unwrap_or(SearchOrder::Newest),
+                            mode: query(url, "mode")
+                                .and_then(|mode| SearchMode::from_query(&mode))
+                                .unwrap_or(SearchMode::All),
+                            since: query(url, "scd").and_then(|date| date.parse().ok()),
+                            until: query(url, "ecd").and_then(|date| date.parse().ok()),
+                        },
+                        max_pages: None,
+                    }),
+                    ["bookmark_new_illust.php"] => Some(Input::FollowLatest { until: None }),
+                    ["member_illust.php"] | ["member.php"] => {
+                        match (query(url, "illust_id"), query(url, "id")) {
+                            (Some(id), _) => Some(Input::Illust(parse_id(&id)?)),
+                            (None, Some(id)) => Some(Input::Profile(parse_id(&id)?)),
+                            (None, None) => None,
+                        }
+                    }
+                    _ => None,
+                }
+            }
+            // e.g. `/img-zip-ugoira/img/2022/01/02/03/04/05/12345678_ugoira1920x1080.zip`
+            "i.pximg.net" => {
+                let name = segments.last()?;
+                let end = name.find(|c: char| !c.is_ascii_digit())?;
+                Some(Input::Illust(parse_id(&name[..end])?))
+            }
+            "pixiv.me" if !segments.is_empty() => Some(Input::ShortLink(url.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// Reads inputs from a file, or stdin if the path is `-`, one per line.
+/// Text from `#` at the line start or after whitespace is a comment
+pub async fn read_input_file(path: &Path) -> Result<Vec<Input>> {
+    let data = if path == Path::new("-") {
+        let mut data = String::new();
+        tokio::io::stdin().read_to_string(&mut data).await?;
+        data
+    } else {
+        tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("failed to read input file {path:?}"))?
+    };
+
+    let mut inputs = Vec::new();
+    let mut errors = Vec::new();
+    for (n, line) in data.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match line.parse() {
+            Ok(input) => inputs.push(input),
+            Err(e) => errors.push(format!("{}:{}: {e}", path.display(), n + 1)),
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!("invalid lines in input file:\n{}", errors.join("\n"));
+    }
+
+    Ok(inputs)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let comment = line
+        .char_indices()
+        .find(|(i, c)| *c == '#' && (*i == 0 || line[..*i].ends_with(char::is_whitespace)));
+    match comment {
+        Some((i, _)) => &line[..i],
+        None => line,
+    }
+}
+
+fn parse_id<T>(s: &str) -> Option<T>
+where
+    T: From<u32>,
+{
+    s.parse::<u32>().ok().map(T::from)
+}
+
+fn decode(segment: &str) -> Option<String> {
+    percent_encoding::percent_decode_str(segment)
+        .decode_utf8()
+        .ok()
+        .map(|s| s.into_owned())
+}
+
+fn query(url: &Url, key: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+
+    use super::read_input_file;
+    use super::strip_comment;
+    use super::Input;
+    use crate::pixiv::types::BookmarkRest;
+    use crate::pixiv::types::IllustId;
+    use crate::pixiv::types::ProfileId;
+
+    fn illust(id: u32) -> Option<Input> {
+        Some(Input::Illust(IllustId::from(id)))
+    }
+
+    fn profile(id: u32) -> Option<Input> {
+        Some(Input::Profile(ProfileId::from(id)))
+    }
+
+    #[test]
+    fn parses_urls() {
+        let zip = "https://i.pximg.net/img-zip-ugoira/img/2022/01/02/03/04/05/\
+                   12345_ugoira1920x1080.zip";
+        let cases = [
+            ("https://www.pixiv.net/artworks/12345", illust(12345)),
+            ("https://www.pixiv.net/en/artworks/12345", illust(12345)),
+            ("https://www.pixiv.net/zh-tw/artworks/12345", illust(12345)),
+            ("https://pixiv.net/artworks/12345/", illust(12345)),
+            ("https://www.pixiv.net/users/678", profile(678)),
+            ("https://www.pixiv.net/en/users/678", profile(678)),
+            (
+                "https://www.pixiv.net/users/678/illustrations",
+                profile(678),
+            ),
+            (
+                "https://www.pixiv.net/member_illust.php?mode=medium&illust_id=12345",
+                illust(12345),
+            ),
+            ("https://www.pixiv.net/member.php?id=678", profile(678)),
+            (zip, illust(12345)),
+            (
+                "https://pixiv.me/someone",
+                Some(Input::ShortLink(
+                    Url::parse("https://pixiv.me/someone").unwrap(),
+                )),
+            ),
+            ("https://pixiv.me/", None),
+            ("https://www.pixiv.net/artworks/abc", None),
+            ("https://www.pixiv.net/member_illust.php", None),
+            ("https://example.com/artworks/12345", None),
+        ];
+
+        for (url, expected) in cases {
+            assert_eq!(
+                Input::from_url(&Url::parse(url).unwrap()),
+                expected,
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_bookmark_tags() {
+        let cases = [
+            (
+                "https://www.pixiv.net/users/678/bookmarks/artworks",
+                BookmarkRest::Show,
+                None,
+            ),
+            (
+                "https://www.pixiv.net/en/users/678/bookmarks/artworks?rest=hide",
+                BookmarkRest::Hide,
+                None,
+            ),
+            (
+                "https://www.pixiv.net/users/678/bookmarks/artworks/%E3%81%86%E3%81%94%E3%81%8F",
+                BookmarkRest::Show,
+                Some("うごく"),
+            ),
+            (
+                "https://www.pixiv.net/users/678/bookmarks/artworks/pixel%20art",
+                BookmarkRest::Show,
+                Some("pixel art"),
+            ),
+        ];
+
+        for (url, rest, tag) in cases {
+            let expected = Input::Bookmarks {
+                user_id: ProfileId::from(678),
+                rest,
+                tag: tag.map(str::to_string),
+            };
+            assert_eq!(
+                Input::from_url(&Url::parse(url).unwrap()),
+                Some(expected),
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_ids_and_urls_without_scheme() {
+        let cases = [
+            ("12345", illust(12345)),
+            (" 12345 ", illust(12345)),
+            ("www.pixiv.net/artworks/12345", illust(12345)),
+            ("pixiv.net/en/users/678", profile(678)),
+            ("not an input", None),
+        ];
+
+        for (s, expected) in cases {
+            assert_eq!(s.parse::<Input>().ok(), expected, "{s}");
+        }
+    }
+
+    #[test]
+    fn strips_comments() {
+        let cases = [
+            ("12345", "12345"),
+            ("# users to follow", ""),
+            ("12345 # favourite", "12345 "),
+            ("12345\t#tab", "12345\t"),
+            // `#` inside a URL is its fragment, not a comment
+            (
+                "https://www.pixiv.net/artworks/12345#comments",
+                "https://www.pixiv.net/artworks/12345#comments",
+            ),
+        ];
+
+        for (line, expected) in cases {
+            assert_eq!(strip_comment(line), expected, "{line}");
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_input_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("inputs.txt");
+        std::fs::write(
+            &path,
+            "# weekly\n12345\n\n  https://www.pixiv.net/users/678  # artist\n",
+        )
+        .unwrap();
+
+        let inputs = read_input_file(&path).await.unwrap();
+        assert_eq!(inputs, [illust(12345).unwrap(), profile(678).unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn reports_invalid_lines_with_numbers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("inputs.txt");
+        std::fs::write(&path, "12345\n# fine\nnope\n678\nexample.com/1 # bad\n").unwrap();
+
+        let e = read_input_file(&path).await.unwrap_err().to_string();
+        let lines = e.lines().skip(1).collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2, "{e}");
+        assert!(
+            lines[0].starts_with(&format!("{}:3: ", path.display())),
+            "{e}"
+        );
+        assert!(
+            lines[1].starts_with(&format!("{}:5: ", path.display())),
+            "{e}"
+        );
+    }
+}
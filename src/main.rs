@@ -1,163 +1,857 @@
 #![allow(dead_code)]
 
+mod details;
 mod encoder;
+mod history;
 mod illust;
+mod input;
 mod pixiv;
 mod save;
+mod summary;
+mod template;
 mod ugoira;
 mod util;
+mod validate;
 
+use std::collections::HashSet;
+use std::num::NonZeroU32;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
+use anyhow::bail;
 use anyhow::Result;
+use bytes::Bytes;
+use chrono::NaiveDate;
 use clap::ArgEnum;
 use clap::Parser;
+use clap::Subcommand;
+use futures::stream::BoxStream;
 use futures::StreamExt;
+use par_stream::NumWorkers;
+use par_stream::ParParams;
+use par_stream::ParParamsConfig;
 
-use crate::encoder::EncodeParams;
+use crate::details::StreamIllustDetailsProvider;
+use crate::encoder::native::GifPalette;
+use crate::encoder::FfmpegIo;
+use crate::encoder::FrameRate;
 use crate::encoder::StreamUgoiraEncoder;
-use crate::illust::IllustIdProvider;
+use crate::encoder::WebpQuality;
+use crate::history::read_records;
+use crate::history::HISTORY;
+use crate::history::HISTORY_FILE;
 use crate::illust::MasterIllustIdProvider;
+use crate::illust::NotUgoira;
+use crate::illust::Unavailable;
+use crate::input::read_input_file;
+use crate::input::Input;
+use crate::pixiv::types::BookmarkRest;
+use crate::pixiv::types::IllustDetails;
 use crate::pixiv::types::IllustId;
 use crate::pixiv::types::ProfileId;
+use crate::pixiv::types::SearchMode;
+use crate::pixiv::types::SearchOrder;
+use crate::pixiv::types::SearchQuery;
+use crate::pixiv::RetryPolicy;
+use crate::save::file_path;
+use crate::save::is_saved;
+use crate::save::ExistingPolicy;
+use crate::save::SaveData;
+use crate::save::SaveFile;
 use crate::save::SaveParams;
 use crate::save::StreamUgoiraSaver;
+use crate::summary::SUMMARY;
+use crate::template::FilenameFields;
+use crate::template::FilenameTemplate;
+use crate::ugoira::StreamLocalUgoiraDataProvider;
 use crate::ugoira::StreamUgoiraDataProvider;
+use crate::util::JobLimit;
+use crate::validate::StreamUgoiraValidator;
+use crate::validate::Validated;
 
 #[derive(ArgEnum, Clone, Copy, Debug)]
 enum Format {
     Webm,
     Gif,
+    Apng,
+    Webp,
+    Mp4,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+enum Output {
+    /// Only the file encoded to `--format`
+    Encoded,
+    /// Only the original zip, with the frame list added to it as `animation.json`
+    Raw,
+    /// Both of the above
+    Both,
+}
+
+impl Output {
+    fn encoded(self) -> bool {
+        matches!(self, Output::Encoded | Output::Both)
+    }
+
+    fn raw(self) -> bool {
+        matches!(self, Output::Raw | Output::Both)
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+enum Backend {
+    Ffmpeg,
+    Native,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+enum BookmarkVisibility {
+    Public,
+    /// Only visible to the owner, requires `--cookie` of their session
+    Private,
+    /// Both of the above
+    All,
+}
+
+impl BookmarkVisibility {
+    fn rests(self) -> &'static [BookmarkRest] {
+        match self {
+            BookmarkVisibility::Public => &[BookmarkRest::Show],
+            BookmarkVisibility::Private => &[BookmarkRest::Hide],
+            BookmarkVisibility::All => &[BookmarkRest::Show, BookmarkRest::Hide],
+        }
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+enum SearchSort {
+    Newest,
+    Oldest,
+    /// Requires a premium account
+    Popular,
+}
+
+impl SearchSort {
+    fn order(self) -> SearchOrder {
+        match self {
+            SearchSort::Newest => SearchOrder::Newest,
+            SearchSort::Oldest => SearchOrder::Oldest,
+            SearchSort::Popular => SearchOrder::Popular,
+        }
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+enum SearchRating {
+    All,
+    Safe,
+    /// Requires an account with R-18 works enabled
+    R18,
+}
+
+impl SearchRating {
+    fn mode(self) -> SearchMode {
+        match self {
+            SearchRating::All => SearchMode::All,
+            SearchRating::Safe => SearchMode::Safe,
+            SearchRating::R18 => SearchMode::R18,
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+// Parsed once, so the size of the download arguments doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Download ugoira and save them to OUT_PATH
+    Download(Args),
+    /// Show the download history
+    History(HistoryArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct HistoryArgs {
+    /// Only illustrations that failed
+    #[clap(long)]
+    failed: bool,
+
+    /// Only illustrations of the given user
+    #[clap(short, long)]
+    user_id: Option<u32>,
+
+    /// Every record, instead of the latest one of each illustration
+    #[clap(long)]
+    all: bool,
+
+    /// History file, or OUT_PATH it was kept in
+    path: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
     #[clap(short, long, arg_enum, default_value_t = Format::Gif)]
     format: Format,
 
+    #[clap(short, long, arg_enum, default_value_t = Output::Encoded)]
+    output: Output,
+
+    #[clap(short, long, arg_enum, default_value_t = Backend::Ffmpeg)]
+    backend: Backend,
+
+    #[clap(long, arg_enum, default_value_t = GifPalette::Global)]
+    gif_palette: GifPalette,
+
+    #[clap(long, arg_enum, default_value_t = FrameRate::Variable)]
+    frame_rate: FrameRate,
+
+    /// How frames are passed to ffmpeg and the output is read back.
+    /// With `pipe`, WebM, GIF and (fragmented) MP4 stream end to end,
+    /// APNG and WebP are still written to a file in --spool-dir
+    #[clap(long, arg_enum, default_value_t = FfmpegIo::TempDir)]
+    ffmpeg_io: FfmpegIo,
+
+    /// Encode WebP lossy with the given quality (0-100) instead of lossless
+    #[clap(long)]
+    webp_quality: Option<u8>,
+
     #[clap(short, long)]
     profile_ids: Vec<u32>,
 
     #[clap(short, long)]
     illust_ids: Vec<u32>,
 
+    /// User whose bookmarked works to download
+    #[clap(long)]
+    bookmarks: Vec<u32>,
+
+    #[clap(long, arg_enum, default_value_t = BookmarkVisibility::Public)]
+    bookmark_visibility: BookmarkVisibility,
+
+    /// Only bookmarks with the given bookmark tag
+    #[clap(long)]
+    bookmark_tag: Option<String>,
+
+    /// New works of followed users, back to the newest one seen by an earlier run
+    /// as the history records it, short of any that failed. Requires `--cookie`
+    #[clap(long)]
+    follow_latest: bool,
+
+    /// Page `--follow-latest` back to works posted on this date (YYYY-MM-DD) instead
+    #[clap(long, requires = "follow-latest")]
+    follow_latest_until: Option<NaiveDate>,
+
+    /// Tag expression to search ugoira for, e.g. `pixelart OR ドット絵`
+    #[clap(long)]
+    search: Vec<String>,
+
+    #[clap(long, arg_enum, default_value_t = SearchSort::Newest, requires = "search")]
+    search_order: SearchSort,
+
+    /// Works of which age rating to search, R-18 requires `--cookie`
+    #[clap(long, arg_enum, default_value_t = SearchRating::All, requires = "search")]
+    search_mode: SearchRating,
+
+    /// Only search works posted on or after this date (YYYY-MM-DD)
+    #[clap(long, requires = "search")]
+    search_since: Option<NaiveDate>,
+
+    /// Only search works posted on or before this date (YYYY-MM-DD)
+    #[clap(long, requires = "search")]
+    search_until: Option<NaiveDate>,
+
+    /// Pages of search results to go through, 60 works each [default: all]
+    #[clap(long, requires = "search")]
+    search_pages: Option<NonZeroU32>,
+
+    /// File of ids and URLs like INPUTS, one per line with `#` comments, or `-` for stdin
+    #[clap(long)]
+    input_file: Vec<PathBuf>,
+
+    /// Previously downloaded ugoira zip, with metadata either in `{name}.json`
+    /// next to it or in `animation.json` inside of it
+    #[clap(short, long)]
+    local: Vec<PathBuf>,
+
     #[clap(short, long)]
     cookie: Option<String>,
 
+    /// Drop unreadable frames and resize frames of odd dimensions,
+    /// instead of failing the illustration
+    #[clap(long)]
+    repair: bool,
+
+    /// Limit of pixiv API requests per second, 0 for no limit
+    #[clap(long, default_value_t = 2.0)]
+    max_requests_per_second: f64,
+
+    /// Limit of i.pximg.net downloads per second, 0 for no limit
+    #[clap(long, default_value_t = 4.0)]
+    max_downloads_per_second: f64,
+
+    /// Attempts per pixiv request before giving up on it
+    #[clap(long, default_value_t = 5)]
+    max_attempts: u32,
+
+    /// Delay before the first retry in milliseconds, doubled on every next one
+    #[clap(long, default_value_t = 1000)]
+    retry_delay: u64,
+
+    /// Upper bound of random delay added to retries, in milliseconds
+    #[clap(long, default_value_t = 500)]
+    retry_jitter: u64,
+
+    /// Longest wait before a retry in milliseconds, also capping `Retry-After` of the server
+    #[clap(long, default_value_t = 60000)]
+    max_retry_delay: u64,
+
+    /// Illustrations downloaded at once, details and local zips included
+    #[clap(long, default_value_t = NonZeroUsize::new(4).unwrap())]
+    download_jobs: NonZeroUsize,
+
+    /// Illustrations validated and encoded at once [default: number of CPUs]
+    #[clap(long)]
+    encode_jobs: Option<NonZeroUsize>,
+
+    /// Illustrations saved at once
+    #[clap(long, default_value_t = NonZeroUsize::new(2).unwrap())]
+    save_jobs: NonZeroUsize,
+
+    /// Illustrations buffered before and after every stage, bounding memory use
+    /// when a stage is slower than the previous one
+    #[clap(long, default_value_t = 2)]
+    queue_size: usize,
+
+    /// Directory for downloads in progress, partial ones are resumed on the next run,
+    /// and for APNG and WebP files encoded with `--ffmpeg-io pipe`
+    /// [default: ugokuna in the system temporary directory]
+    #[clap(long)]
+    spool_dir: Option<PathBuf>,
+
+    /// Save illustrations again even if they were saved before
+    #[clap(long, conflicts_with_all = &["skip-existing", "update-if-changed"])]
+    overwrite: bool,
+
+    /// Skip illustrations saved in the same format according to the history,
+    /// or whose files all exist in OUT_PATH (default)
+    #[clap(long, conflicts_with = "update-if-changed")]
+    skip_existing: bool,
+
+    /// Skip illustrations like --skip-existing,
+    /// unless the work was updated on pixiv after they were saved
+    #[clap(long)]
+    update_if_changed: bool,
+
+    /// Saved file path relative to OUT_PATH, without extension.
+    /// Fields: {id}, {title}, {user_id}, {user_name}, {date}, {tags}, {format};
+    /// `/` separates subdirectories
+    #[clap(long, default_value = "{id}")]
+    filename: FilenameTemplate,
+
+    /// File recording every processed illustration, also used to skip saved ones
+    /// [default: .ugokuna-history.jsonl in OUT_PATH]
+    #[clap(long)]
+    history: Option<PathBuf>,
+
     out_path: PathBuf,
+
+    /// Illustration ids, or pixiv URLs of works (`artworks/{id}`, `member_illust.php`,
+    /// ugoira zips on i.pximg.net), users (`users/{id}`, `pixiv.me/{name}`),
+    /// bookmarks (`users/{id}/bookmarks/artworks`) and tag searches (`tags/{word}`)
+    inputs: Vec<Input>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let Cli { command } = Cli::parse();
+
+    match command {
+        Command::Download(args) => download(args).await,
+        Command::History(args) => history(args).await,
+    }
+}
+
+async fn history(args: HistoryArgs) -> Result<()> {
+    let HistoryArgs {
+        failed,
+        user_id,
+        all,
+        path,
+    } = args;
+
+    let path = match tokio::fs::metadata(&path).await {
+        Ok(metadata) if metadata.is_dir() => path.join(HISTORY_FILE),
+        _ => path,
+    };
+    if tokio::fs::metadata(&path).await.is_err() {
+        bail!("no history found at {path:?}");
+    }
+
+    let mut records = read_records(&path).await?;
+    if !all {
+        // Keep the latest record of every illustration, in the order they were written
+        let mut seen = HashSet::new();
+        records.reverse();
+        records.retain(|record| seen.insert(record.illust_id));
+        records.reverse();
+    }
+
+    let user_id = user_id.map(ProfileId::from);
+    for record in records {
+        if record.matches(failed, user_id) {
+            println!("{record}");
+        }
+    }
+
+    Ok(())
+}
+
+async fn download(args: Args) -> Result<()> {
     let Args {
         format,
+        output,
+        backend,
+        gif_palette,
+        frame_rate,
+        ffmpeg_io,
+        webp_quality,
         profile_ids,
         illust_ids,
+        bookmarks,
+        bookmark_visibility,
+        bookmark_tag,
+        follow_latest,
+        follow_latest_until,
+        search,
+        search_order,
+        search_mode,
+        search_since,
+        search_until,
+        search_pages,
+        input_file,
+        local,
         cookie,
+        repair,
+        max_requests_per_second,
+        max_downloads_per_second,
+        max_attempts,
+        retry_delay,
+        retry_jitter,
+        max_retry_delay,
+        download_jobs,
+        encode_jobs,
+        save_jobs,
+        queue_size,
+        spool_dir,
+        overwrite,
+        skip_existing,
+        update_if_changed,
+        filename,
+        history,
         out_path,
-    } = Args::parse();
+        inputs,
+    } = args;
 
-    let profile_ids = profile_ids
+    let mut inputs = illust_ids
         .into_iter()
-        .map(ProfileId::from)
+        .map(|id| Input::Illust(IllustId::from(id)))
+        .chain(
+            profile_ids
+                .into_iter()
+                .map(|id| Input::Profile(ProfileId::from(id))),
+        )
+        .chain(bookmarks.into_iter().flat_map(|id| {
+            let tag = bookmark_tag.clone();
+            bookmark_visibility
+                .rests()
+                .iter()
+                .map(move |&rest| Input::Bookmarks {
+                    user_id: ProfileId::from(id),
+                    rest,
+                    tag: tag.clone(),
+                })
+        }))
+        .chain(follow_latest.then_some(Input::FollowLatest {
+            until: follow_latest_until,
+        }))
+        .chain(search.into_iter().map(|word| Input::Search {
+            query: SearchQuery {
+                word,
+                order: search_order.order(),
+                mode: search_mode.mode(),
+                since: search_since,
+                until: search_until,
+            },
+            max_pages: search_pages,
+        }))
+        .chain(inputs)
         .collect::<Vec<_>>();
-    let illust_ids = illust_ids
+    for path in input_file {
+        inputs.extend(read_input_file(&path).await?);
+    }
+    if cookie.is_none() && inputs.iter().any(Input::requires_login) {
+        bail!("private bookmarks, new works of followed users and R-18 search require --cookie");
+    }
+    let local = local
         .into_iter()
-        .map(IllustId::from)
-        .collect::<Vec<_>>();
+        .map(|path| Ok((crate::ugoira::local_illust_id(&path)?, path)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let webp_quality = match webp_quality {
+        Some(quality) if quality > 100 => bail!("WebP quality must be in the range of 0 to 100"),
+        Some(quality) => WebpQuality::Lossy(quality),
+        None => WebpQuality::Lossless,
+    };
+
+    // Fail early on format and backend combinations that can't be encoded
+    let backend = encode_backend(format, backend, gif_palette, webp_quality)?;
 
     tokio::fs::create_dir_all(&out_path).await?;
 
+    let format_name = format.to_possible_value().unwrap().get_name();
+    let history_format = match output {
+        Output::Encoded => format_name.to_string(),
+        Output::Raw => "zip".to_string(),
+        Output::Both => format!("{format_name}+zip"),
+    };
+    let history = history.unwrap_or_else(|| out_path.join(HISTORY_FILE));
+    HISTORY.open(&history, history_format).await?;
+
     if let Some(cookie) = cookie {
         pixiv::CLIENT.login(cookie);
     }
+    let rate_limit = |rate: f64| (rate > 0.0).then_some(rate);
+    pixiv::CLIENT.set_api_rate_limit(rate_limit(max_requests_per_second));
+    pixiv::CLIENT.set_pximg_rate_limit(rate_limit(max_downloads_per_second));
+    pixiv::CLIENT.set_retry_policy(RetryPolicy {
+        max_attempts,
+        base_delay: Duration::from_millis(retry_delay),
+        jitter: Duration::from_millis(retry_jitter),
+        max_delay: Duration::from_millis(max_retry_delay),
+    });
+
+    let existing = match (overwrite, skip_existing, update_if_changed) {
+        (true, _, _) => ExistingPolicy::Overwrite,
+        (_, _, true) => ExistingPolicy::UpdateIfChanged,
+        _ => ExistingPolicy::Skip,
+    };
+    let spool_dir = spool_dir.unwrap_or_else(|| std::env::temp_dir().join("ugokuna"));
+
+    let stage_params = |jobs: Option<NonZeroUsize>| -> ParParams {
+        ParParamsConfig::Manual {
+            num_workers: NumWorkers::from(jobs.map(NonZeroUsize::get)),
+            buf_size: queue_size.into(),
+        }
+        .into()
+    };
+    let download_params = stage_params(Some(download_jobs));
+    // Details, downloads and local zips all count against `--download-jobs`
+    let download_limit = JobLimit::new(download_jobs.get());
+    let encode_params = stage_params(encode_jobs);
+    let save_params = stage_params(Some(save_jobs));
+
+    // Details cost a request per illustration, so they are only fetched for what needs them
+    let details_required =
+        filename.needs_details() || matches!(existing, ExistingPolicy::UpdateIfChanged);
+
+    let render_path = move |i: IllustId, d: Option<&IllustDetails>| {
+        let fields = FilenameFields::new(i, format_name, d);
+        out_path.join(filename.render(&fields))
+    };
 
     // 1. Get illustrations IDs
-    let illust_id_provider = MasterIllustIdProvider::new(illust_ids, profile_ids);
+    let illust_id_provider = MasterIllustIdProvider::new(inputs);
     let illusts_stream = illust_id_provider.into_stream();
     let illusts_stream = Box::pin(illusts_stream.filter_map(|r| async move {
         match r {
             Ok(o) => {
-                println!("Added illustration {o} to download queue");
+                println!("Added illustration {} to download queue", o.illust_id);
+                if let Some(user_id) = o.user_id {
+                    HISTORY.listed(o.illust_id, user_id);
+                }
                 Some(o)
             }
+            Err(e) if e.is::<NotUgoira>() => {
+                SUMMARY.skipped();
+                None
+            }
+            Err(e) if e.is::<Unavailable>() => {
+                println!("Skipped {e}");
+                SUMMARY.skipped();
+                None
+            }
             Err(e) => {
                 eprintln!("Error while obtaining illustration id: {e}");
+                SUMMARY.failed();
                 None
             }
         }
     }));
 
-    // 2. Download frames
-    let ugoira_data_stream = illusts_stream;
-    let ugoira_data_stream = Box::pin(ugoira_data_stream.map(|i| (i, i)));
-    let ugoira_data_provider = StreamUgoiraDataProvider::new(ugoira_data_stream);
-    let ugoira_data_stream = ugoira_data_provider.into_stream();
-    let ugoira_data_stream = Box::pin(ugoira_data_stream.filter_map(|(i, r)| async move {
+    // 2. Get illustrations details
+    let illust_details_stream = illusts_stream;
+    let illust_details_stream = Box::pin(illust_details_stream.map(move |o| {
+        let i = o.illust_id;
+        (i, i, details_required || !o.checked)
+    }));
+    let illust_details_provider = StreamIllustDetailsProvider::new(
+        illust_details_stream,
+        download_limit.clone(),
+        download_params,
+    );
+    let illust_details_stream = illust_details_provider.into_stream();
+    let illust_details_stream = Box::pin(illust_details_stream.filter_map(|(i, r)| async move {
         match r {
-            Ok(o) => {
-                println!("Added illustration {i} to encode queue");
-                Some((i, o))
+            Ok(o) => Some((i, o)),
+            Err(e) if e.is::<NotUgoira>() => {
+                println!("Skipped {e}");
+                SUMMARY.skipped();
+                None
             }
             Err(e) => {
-                eprintln!("Error while obtaining illustration {i} ugoira frames: {e:?}");
+                eprintln!("Error while obtaining illustration {i} details: {e:?}");
+                HISTORY.failed(i, None, None, &e).await;
+                SUMMARY.failed();
                 None
             }
         }
     }));
 
-    // 3. Call ffmpeg
-    let ugoira_encoder_stream = ugoira_data_stream;
-    let ugoira_encoder_stream = Box::pin(ugoira_encoder_stream.map(move |(i, f)| {
-        let params: Box<dyn EncodeParams + Send> = match format {
-            Format::Webm => Box::new(crate::encoder::WebmParams),
-            Format::Gif => Box::new(crate::encoder::GifParams),
-        };
-        (i, f, params)
+    // 3. Skip illustrations that are saved already
+    let existing_stream = illust_details_stream;
+    let existing_render_path = render_path.clone();
+    let existing_stream = Box::pin(existing_stream.filter_map(move |(i, d)| {
+        let path = existing_render_path(i, d.as_ref());
+        let mut params = Vec::<Box<dyn SaveParams + Send>>::new();
+        if output.encoded() {
+            params.push(format_save_params(format));
+        }
+        if output.raw() {
+            params.push(Box::new(crate::save::ZipParams));
+        }
+        let paths = params
+            .iter()
+            .map(|params| file_path(&path, params.as_ref()))
+            .collect::<Vec<_>>();
+
+        async move {
+            let updated = d.as_ref().map(|d| d.upload_date);
+            if HISTORY.is_saved(i, existing, updated) {
+                println!("Skipped illustration {i}, already in history");
+                SUMMARY.skipped();
+                return None;
+            }
+
+            match is_saved(&paths, existing, updated).await {
+                Ok(false) => Some((i, d)),
+                Ok(true) => {
+                    println!("Skipped illustration {i}, already saved");
+                    SUMMARY.skipped();
+                    None
+                }
+                Err(e) => {
+                    eprintln!("Error while checking illustration {i} files: {e:?}");
+                    HISTORY.failed(i, d.as_ref(), None, &e).await;
+                    SUMMARY.failed();
+                    None
+                }
+            }
+        }
     }));
-    let ugoira_encoder = StreamUgoiraEncoder::new(ugoira_encoder_stream);
-    let ugoira_encoder_stream = ugoira_encoder.into_stream();
-    let ugoira_encoder_stream = Box::pin(ugoira_encoder_stream.filter_map(|(i, r)| async move {
+
+    // 4. Download frames
+    let ugoira_data_stream = existing_stream;
+    let ugoira_data_stream = Box::pin(ugoira_data_stream.map(|(i, d)| ((i, d), i)));
+    let ugoira_data_provider = StreamUgoiraDataProvider::new(
+        ugoira_data_stream,
+        spool_dir.clone(),
+        download_limit.clone(),
+        download_params,
+    );
+    let ugoira_data_stream = ugoira_data_provider.into_stream();
+    let local_data_stream = Box::pin(futures::stream::iter(local).map(|(i, p)| ((i, None), p)));
+    let local_data_provider =
+        StreamLocalUgoiraDataProvider::new(local_data_stream, download_limit, download_params);
+    let local_data_stream = local_data_provider.into_stream();
+    let ugoira_data_stream = futures::stream::select(ugoira_data_stream, local_data_stream);
+    let ugoira_data_stream = Box::pin(ugoira_data_stream.filter_map(|((i, d), r)| async move {
         match r {
             Ok(o) => {
-                println!("Added illustration {i} to save queue");
-                Some((i, o))
+                println!("Added illustration {i} to validate queue");
+                Some((i, d, o))
             }
             Err(e) => {
-                eprintln!("Error while encoding illustration {i}: {e:?}");
+                eprintln!("Error while obtaining illustration {i} ugoira frames: {e:?}");
+                HISTORY.failed(i, d.as_ref(), None, &e).await;
+                SUMMARY.failed();
                 None
             }
         }
     }));
 
-    // 4. Save files
-    let ugoira_saver_stream = ugoira_encoder_stream;
-    let ugoira_saver_stream = Box::pin(ugoira_saver_stream.map(move |(i, b)| {
-        let out_path = out_path.clone();
-        let params: Box<dyn SaveParams + Send> = match format {
-            Format::Webm => Box::new(crate::save::WebmParams),
-            Format::Gif => Box::new(crate::save::GifParams),
-        };
-        (i, b, out_path, params)
+    // 5. Validate frames, unless they aren't encoded
+    let ugoira_validator_stream = ugoira_data_stream;
+    // The raw zip keeps pixiv's frame list, not the one repaired by validation
+    let ugoira_validator_stream = Box::pin(ugoira_validator_stream.map(move |(i, d, u)| {
+        let raw = output
+            .raw()
+            .then(|| (u.archive.clone(), u.animation_json()));
+        ((i, d, u.original_src.clone(), raw), u)
     }));
-    let ugoira_encoder = StreamUgoiraSaver::new(ugoira_saver_stream);
-    let ugoira_saver_stream = ugoira_encoder.into_stream();
-    let ugoira_saver_stream = Box::pin(ugoira_saver_stream.filter_map(|(i, r)| async move {
-        match r {
-            Ok(o) => {
-                println!("Finished processing illustration {i}");
-                Some((i, o))
+    let ugoira_validator_stream: BoxStream<'static, (_, Result<Validated>)> = if output.encoded() {
+        let ugoira_validator =
+            StreamUgoiraValidator::new(ugoira_validator_stream, repair, encode_params);
+        ugoira_validator.into_stream()
+    } else {
+        Box::pin(ugoira_validator_stream.map(|(i, u)| (i, Ok((u, Vec::new())))))
+    };
+    let ugoira_validator_stream = Box::pin(ugoira_validator_stream.filter_map(
+        |((i, d, url, raw), r)| async move {
+            match r {
+                Ok((o, problems)) => {
+                    for problem in problems {
+                        match problem.is_harmless() {
+                            true => eprintln!("Warning for illustration {i}: {problem}"),
+                            false => eprintln!("Repaired illustration {i}: {problem}"),
+                        }
+                    }
+                    println!("Added illustration {i} to encode queue");
+                    Some((i, d, url, raw, o))
+                }
+                Err(e) => {
+                    eprintln!("Error while validating illustration {i}: {e:?}");
+                    HISTORY.failed(i, d.as_ref(), url, &e).await;
+                    SUMMARY.failed();
+                    None
+                }
             }
-            Err(e) => {
-                eprintln!("Error while saving illustration {i}: {e:?}");
-                None
+        },
+    ));
+
+    // 6. Call ffmpeg
+    let ugoira_encoder_stream = ugoira_validator_stream;
+    let ugoira_encoder_stream =
+        Box::pin(ugoira_encoder_stream.map(|(i, d, url, raw, u)| ((i, d, url, raw), u.frames)));
+    let ugoira_encoder_stream: BoxStream<'static, (_, Result<Option<Bytes>>)> = if output.encoded()
+    {
+        let ugoira_encoder = StreamUgoiraEncoder::new(
+            ugoira_encoder_stream,
+            backend,
+            frame_rate,
+            ffmpeg_io,
+            spool_dir,
+            encode_params,
+        );
+        let ugoira_encoder_stream = ugoira_encoder.into_stream();
+        Box::pin(ugoira_encoder_stream.map(|(i, r)| (i, r.map(Some))))
+    } else {
+        Box::pin(ugoira_encoder_stream.map(|(i, _)| (i, Ok(None))))
+    };
+    let ugoira_encoder_stream = Box::pin(ugoira_encoder_stream.filter_map(
+        |((i, d, url, raw), r)| async move {
+            match r {
+                Ok(o) => {
+                    println!("Added illustration {i} to save queue");
+                    Some((i, d, url, raw, o))
+                }
+                Err(e) => {
+                    eprintln!("Error while encoding illustration {i}: {e:?}");
+                    HISTORY.failed(i, d.as_ref(), url, &e).await;
+                    SUMMARY.failed();
+                    None
+                }
             }
+        },
+    ));
+
+    // 7. Save files
+    let ugoira_saver_stream = ugoira_encoder_stream;
+    let ugoira_saver_stream = Box::pin(ugoira_saver_stream.map(move |(i, d, url, raw, b)| {
+        let path = render_path(i, d.as_ref());
+        let mut files = Vec::<SaveFile>::new();
+        if let Some(b) = b {
+            files.push((b.into(), format_save_params(format)));
+        }
+        if let Some((archive, animation_json)) = raw {
+            let data = SaveData::Archive {
+                archive,
+                animation_json,
+            };
+            files.push((data, Box::new(crate::save::ZipParams)));
         }
+        ((i, d, url), files, path)
     }));
+    let ugoira_encoder = StreamUgoiraSaver::new(ugoira_saver_stream, save_params);
+    let ugoira_saver_stream = ugoira_encoder.into_stream();
+    let ugoira_saver_stream = Box::pin(ugoira_saver_stream.filter_map(
+        |((i, d, url), r)| async move {
+            match r {
+                Ok(o) => {
+                    println!("Finished processing illustration {i}");
+                    HISTORY.finished(i, d.as_ref(), url, o).await;
+                    SUMMARY.finished();
+                    Some(i)
+                }
+                Err(e) => {
+                    eprintln!("Error while saving illustration {i}: {e:?}");
+                    HISTORY.failed(i, d.as_ref(), url, &e).await;
+                    SUMMARY.failed();
+                    None
+                }
+            }
+        },
+    ));
 
     // Run the pipeline until exhausted
     let mut pipeline = ugoira_saver_stream;
     while pipeline.next().await.is_some() {}
+    HISTORY.finish_run().await;
+
+    println!("Done: {SUMMARY}");
 
     Ok(())
 }
+
+fn format_save_params(format: Format) -> Box<dyn SaveParams + Send> {
+    match format {
+        Format::Webm => Box::new(crate::save::WebmParams),
+        Format::Gif => Box::new(crate::save::GifParams),
+        Format::Apng => Box::new(crate::save::ApngParams),
+        Format::Webp => Box::new(crate::save::WebpParams),
+        Format::Mp4 => Box::new(crate::save::Mp4Params),
+    }
+}
+
+fn encode_backend(
+    format: Format,
+    backend: Backend,
+    gif_palette: GifPalette,
+    webp_quality: WebpQuality,
+) -> Result<crate::encoder::Backend> {
+    use crate::encoder::Backend::Ffmpeg;
+    use crate::encoder::Backend::Native;
+
+    let backend = match (format, backend) {
+        (Format::Webm, Backend::Ffmpeg) => Ffmpeg(Arc::new(crate::encoder::WebmParams)),
+        (Format::Gif, Backend::Ffmpeg) => Ffmpeg(Arc::new(crate::encoder::GifParams)),
+        (Format::Gif, Backend::Native) => Native(Arc::new(crate::encoder::native::GifParams {
+            palette: gif_palette,
+        })),
+        (Format::Apng, Backend::Ffmpeg) => Ffmpeg(Arc::new(crate::encoder::ApngParams)),
+        (Format::Apng, Backend::Native) => Native(Arc::new(crate::encoder::native::ApngParams)),
+        (Format::Webp, Backend::Ffmpeg) => Ffmpeg(Arc::new(crate::encoder::WebpParams {
+            quality: webp_quality,
+        })),
+        (Format::Mp4, Backend::Ffmpeg) => Ffmpeg(Arc::new(crate::encoder::Mp4Params)),
+        (format, backend) => bail!("{format:?} can't be encoded with {backend:?} backend"),
+    };
+
+    Ok(backend)
+}
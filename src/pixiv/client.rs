@@ -1,4 +1,9 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::Path;
 use std::sync::Arc;
+use std::sync::RwLock;
 
 use anyhow::Result;
 use bytes::Bytes;
@@ -6,14 +11,32 @@ use lazy_static::lazy_static;
 use reqwest::cookie::Jar;
 use reqwest::header::HeaderMap;
 use reqwest::header::HeaderValue;
+use reqwest::header::CONTENT_RANGE;
+use reqwest::header::RANGE;
+use reqwest::RequestBuilder;
+use reqwest::StatusCode;
 use reqwest::Url;
 use serde::de::DeserializeOwned;
+use tokio::fs::File;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
 
 use super::error::Error;
+use super::rate_limit::RateLimiter;
 use super::response::Response;
+use super::retry::is_transient_error;
+use super::retry::is_transient_status;
+use super::retry::RetryPolicy;
+use super::types::BookmarkRest;
+use super::types::Bookmarks;
+use super::types::FollowLatest;
+use super::types::IllustDetails;
 use super::types::IllustId;
 use super::types::Profile;
 use super::types::ProfileId;
+use super::types::ProfileIllusts;
+use super::types::SearchQuery;
+use super::types::SearchResults;
 use super::types::UgoiraMeta;
 
 lazy_static! {
@@ -22,10 +45,17 @@ lazy_static! {
 
 const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; rv:91.0) Gecko/20100101 Firefox/91.0";
 const PIXIV_ROOT: &str = "https://www.pixiv.net/";
+/// Host serving images and ugoira zips, rate limited separately from the API
+const PXIMG_HOST: &str = "i.pximg.net";
+pub const PROFILE_ILLUSTS_BATCH: usize = 48;
+const BOOKMARKS_BATCH: usize = 48;
 
 pub struct Client {
     jar: Arc<Jar>,
     http: reqwest::Client,
+    retry_policy: RwLock<RetryPolicy>,
+    api_limiter: RateLimiter,
+    pximg_limiter: RateLimiter,
 }
 
 impl Client {
@@ -51,7 +81,13 @@ impl Client {
             .default_headers(default_headers)
             .build()?;
 
-        Ok(Self { jar, http })
+        Ok(Self {
+            jar,
+            http,
+            retry_policy: RwLock::default(),
+            api_limiter: RateLimiter::new(None),
+            pximg_limiter: RateLimiter::new(None),
+        })
     }
 
     pub fn login(&self, cookie: impl AsRef<str>) {
@@ -61,44 +97,461 @@ impl Client {
         self.jar.add_cookie_str(&cookie, &url)
     }
 
+    pub fn set_retry_policy(&self, retry_policy: RetryPolicy) {
+        *self.retry_policy.write().unwrap() = retry_policy;
+    }
+
+    /// Limits requests to pixiv API per second, `None` to disable
+    pub fn set_api_rate_limit(&self, rate: Option<f64>) {
+        self.api_limiter.set_rate(rate);
+    }
+
+    /// Limits requests to i.pximg.net per second, `None` to disable
+    pub fn set_pximg_rate_limit(&self, rate: Option<f64>) {
+        self.pximg_limiter.set_rate(rate);
+    }
+
     pub async fn profile(&self, id: ProfileId) -> Result<Profile> {
         let url = format!("{PIXIV_ROOT}ajax/user/{id}/profile/all");
-        self.get(url).await
+        self.get(url, format!("profile {id}")).await
+    }
+
+    /// Short info on the given works of the user, up to [`PROFILE_ILLUSTS_BATCH`] at once
+    pub async fn profile_illusts(
+        &self,
+        id: ProfileId,
+        illust_ids: &[IllustId],
+    ) -> Result<ProfileIllusts> {
+        let mut url = Url::parse(&format!("{PIXIV_ROOT}ajax/user/{id}/profile/illusts"))?;
+        {
+            let mut query = url.query_pairs_mut();
+            for illust_id in illust_ids {
+                query.append_pair("ids[]", &illust_id.to_string());
+            }
+            query.append_pair("work_category", "illust");
+            query.append_pair("is_first_page", "0");
+        }
+        self.get(url, format!("profile {id}")).await
+    }
+
+    /// Up to [`BOOKMARKS_BATCH`] bookmarks of the user starting at `offset`,
+    /// only those with the bookmark tag if given
+    pub async fn bookmarks(
+        &self,
+        id: ProfileId,
+        rest: BookmarkRest,
+        tag: Option<&str>,
+        offset: usize,
+    ) -> Result<Bookmarks> {
+        let mut url = Url::parse(&format!("{PIXIV_ROOT}ajax/user/{id}/illusts/bookmarks"))?;
+        url.query_pairs_mut()
+            .append_pair("tag", tag.unwrap_or_default())
+            .append_pair("offset", &offset.to_string())
+            .append_pair("limit", &BOOKMARKS_BATCH.to_string())
+            .append_pair("rest", rest.as_str());
+        self.get(url, format!("bookmarks of user {id}")).await
+    }
+
+    /// Page of new works by the users followed by the logged in one, starting from 1
+    pub async fn follow_latest(&self, page: u32) -> Result<FollowLatest> {
+        let url = format!("{PIXIV_ROOT}ajax/follow_latest/illust?p={page}&mode=all");
+        self.get(url, format!("page {page} of followed users' works"))
+            .await
+    }
+
+    /// Page of ugoira matching the query, starting from 1
+    pub async fn search_ugoira(&self, query: &SearchQuery, page: u32) -> Result<SearchResults> {
+        let mut url = Url::parse(&format!("{PIXIV_ROOT}ajax/search/illustrations/"))?;
+        url.path_segments_mut()
+            .unwrap()
+            .pop_if_empty()
+            .push(&query.word);
+        {
+            let mut pairs = url.query_pairs_mut();
+            pairs
+                .append_pair("word", &query.word)
+                .append_pair("order", query.order.as_str())
+                .append_pair("mode", query.mode.as_str())
+                .append_pair("p", &page.to_string())
+                .append_pair("s_mode", "s_tag")
+                .append_pair("type", "ugoira");
+            if let Some(since) = query.since {
+                pairs.append_pair("scd", &since.to_string());
+            }
+            if let Some(until) = query.until {
+                pairs.append_pair("ecd", &until.to_string());
+            }
+        }
+        self.get(url, format!("page {page} of search {:?}", query.word))
+            .await
+    }
+
+    pub async fn illust_details(&self, id: IllustId) -> Result<IllustDetails> {
+        let url = format!("{PIXIV_ROOT}ajax/illust/{id}");
+        self.get(url, format!("illustration {id}")).await
     }
 
     pub async fn ugoira_meta(&self, id: IllustId) -> Result<UgoiraMeta> {
         let url = format!("{PIXIV_ROOT}ajax/illust/{id}/ugoira_meta");
-        self.get(url).await
+        self.get(url, format!("illustration {id}")).await
+    }
+
+    /// Follows the redirects of the URL, e.g. of a `pixiv.me` link, to where it leads
+    pub async fn resolve_redirect(&self, url: &Url) -> Result<Url> {
+        self.with_retries(url.as_str(), url, || async move {
+            let response = match self.send(self.http.get(url.clone())).await {
+                Ok(response) => response,
+                Err(attempt) => return attempt,
+            };
+
+            match response.status() {
+                status if status.is_success() => Attempt::Done(response.url().clone()),
+                status => Attempt::Fail(Error::Status(status).into()),
+            }
+        })
+        .await
+    }
+
+    /// Streams the ugoira zip to the file at `path`, without holding it in memory.
+    /// Whatever is already in the file is kept and the rest is requested with `Range`
+    pub async fn download_ugoira(
+        &self,
+        id: IllustId,
+        meta: &UgoiraMeta,
+        path: &Path,
+    ) -> Result<()> {
+        let url = meta.original_src.as_str();
+        self.with_retries(url, format!("illustration {id}"), || async move {
+            match self.download_to(url, path).await {
+                Ok(()) => Attempt::Done(()),
+                Err(attempt) => attempt,
+            }
+        })
+        .await
     }
 
-    pub async fn download_ugoira<'a>(&'a self, meta: &UgoiraMeta) -> Result<Bytes> {
-        let original_src = meta.original_src.clone();
-        let resp = self.http.get(original_src).send().await?;
-        let data = resp.bytes().await?;
+    async fn download_to(&self, url: &str, path: &Path) -> std::result::Result<(), Attempt<()>> {
+        let offset = match tokio::fs::metadata(path).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut request = self.http.get(url);
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={offset}-"));
+        }
+        let mut response = self.send(request).await?;
+
+        let status = response.status();
+        let (start, total) = content_range(response.headers()).unwrap_or_default();
+        let resumed = match status {
+            StatusCode::PARTIAL_CONTENT if start == Some(offset) => true,
+            StatusCode::RANGE_NOT_SATISFIABLE if total == Some(offset) => return Ok(()),
+            // The part on disk doesn't match the archive anymore, start over
+            StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE => {
+                tokio::fs::remove_file(path).await?;
+                return Err(Attempt::Retry(Error::Status(status).into(), None));
+            }
+            status if status.is_success() => false,
+            status => return Err(Attempt::Fail(Error::Status(status).into())),
+        };
+
+        let mut file = if resumed {
+            OpenOptions::new().append(true).open(path).await?
+        } else {
+            File::create(path).await?
+        };
+
+        // Received data is kept for the next attempt, even if the body fails midway
+        let result = loop {
+            match response.chunk().await {
+                Ok(Some(chunk)) => file.write_all(&chunk).await?,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e.into()),
+            }
+        };
+        file.sync_all().await?;
 
-        Ok(data)
+        result
     }
 
-    async fn get<T, S>(&self, url: S) -> Result<T>
+    async fn get<T, S>(&self, url: S, context: impl Display) -> Result<T>
     where
         T: DeserializeOwned,
         S: AsRef<str>,
     {
-        let response = self.http.get(url.as_ref()).send().await?;
-
-        let data = response.bytes().await?;
-        let json = serde_json::from_slice(&data)?;
-
-        match json {
-            Response {
-                error: true,
-                message,
-                ..
-            } => Err(Error::Pixiv(message).into()),
-            Response { body: None, .. } => Err(Error::NoData.into()),
-            Response {
-                body: Some(body), ..
-            } => Ok(body),
+        let (status, data) = self.fetch(url.as_ref(), context).await?;
+        parse_response(status, &data)
+    }
+
+    fn rate_limiter(&self, url: &str) -> &RateLimiter {
+        let url = Url::parse(url).ok();
+        match url.as_ref().and_then(Url::host_str) {
+            Some(PXIMG_HOST) => &self.pximg_limiter,
+            _ => &self.api_limiter,
         }
     }
+
+    /// Sends GET request and reads the whole body
+    async fn fetch(&self, url: &str, context: impl Display) -> Result<(StatusCode, Bytes)> {
+        self.with_retries(url, context, || async move {
+            let response = match self.send(self.http.get(url)).await {
+                Ok(response) => response,
+                Err(attempt) => return attempt,
+            };
+
+            let status = response.status();
+            match response.bytes().await {
+                Ok(data) => Attempt::Done((status, data)),
+                Err(e) => e.into(),
+            }
+        })
+        .await
+    }
+
+    /// Sends the request, sorting out responses with transient statuses
+    async fn send<T>(
+        &self,
+        request: RequestBuilder,
+    ) -> std::result::Result<reqwest::Response, Attempt<T>> {
+        match request.send().await {
+            Ok(response) if is_transient_status(response.status()) => {
+                let status = response.status();
+                let headers = response.headers().clone();
+                Err(Attempt::Retry(Error::Status(status).into(), Some(headers)))
+            }
+            Ok(response) => Ok(response),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Runs attempts of a request, retrying on network errors and transient statuses
+    /// according to the retry policy
+    async fn with_retries<T, F, Fut>(
+        &self,
+        url: &str,
+        context: impl Display,
+        mut attempt_fn: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Attempt<T>>,
+    {
+        let retry_policy = *self.retry_policy.read().unwrap();
+        let rate_limiter = self.rate_limiter(url);
+
+        let mut attempt = 1;
+        loop {
+            rate_limiter.acquire().await;
+
+            let (error, headers) = match attempt_fn().await {
+                Attempt::Done(o) => return Ok(o),
+                Attempt::Retry(error, headers) => (error, headers),
+                Attempt::Fail(error) => return Err(error),
+            };
+
+            if attempt >= retry_policy.max_attempts {
+                return Err(error);
+            }
+
+            let delay = retry_policy.delay(attempt, headers.as_ref());
+            eprintln!(
+                "Attempt {attempt}/{max} for {context} failed: {error}, retrying in {delay:.1?}",
+                max = retry_policy.max_attempts,
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Outcome of a single attempt of a request
+enum Attempt<T> {
+    Done(T),
+    /// Transient failure, retried after the delay from `Retry-After`, if any
+    Retry(anyhow::Error, Option<HeaderMap>),
+    Fail(anyhow::Error),
+}
+
+impl<T> From<std::io::Error> for Attempt<T> {
+    fn from(e: std::io::Error) -> Self {
+        Attempt::Fail(e.into())
+    }
+}
+
+impl<T> From<reqwest::Error> for Attempt<T> {
+    fn from(e: reqwest::Error) -> Self {
+        if is_transient_error(&e) {
+            Attempt::Retry(e.into(), None)
+        } else {
+            Attempt::Fail(e.into())
+        }
+    }
+}
+
+/// Body of the API response, or the error it reports.
+/// Errors such as deleted works come with a message in the body
+fn parse_response<T>(status: StatusCode, data: &[u8]) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    let json = match serde_json::from_slice(data) {
+        Ok(json) => json,
+        Err(_) if !status.is_success() => return Err(Error::Status(status).into()),
+        Err(e) => return Err(e.into()),
+    };
+
+    match json {
+        Response {
+            error: true,
+            message,
+            ..
+        } => Err(Error::Pixiv(message).into()),
+        Response { body: None, .. } => Err(Error::NoData.into()),
+        Response {
+            body: Some(body), ..
+        } => Ok(body),
+    }
+}
+
+/// Start and total length from `Content-Range: bytes {start}-{end}/{total}`,
+/// or `bytes */{total}` of an unsatisfiable range
+fn content_range(headers: &HeaderMap) -> Option<(Option<u64>, Option<u64>)> {
+    let value = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let start = range
+        .split_once('-')
+        .and_then(|(start, _)| start.parse().ok());
+    Some((start, total.parse().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderMap;
+    use reqwest::header::HeaderValue;
+    use reqwest::header::CONTENT_RANGE;
+    use reqwest::StatusCode;
+    use serde_json::Value;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    use super::content_range;
+    use super::parse_response;
+    use super::Attempt;
+    use super::Client;
+    use crate::pixiv::error::Error;
+
+    fn parse(status: u16, data: &str) -> anyhow::Result<Value> {
+        parse_response(StatusCode::from_u16(status).unwrap(), data.as_bytes())
+    }
+
+    #[test]
+    fn reads_body() {
+        let body = parse(200, r#"{"error":false,"message":"","body":{"id":"1"}}"#).unwrap();
+        assert_eq!(body["id"], "1");
+    }
+
+    #[test]
+    fn reports_deleted_works() {
+        let message = "該当作品は削除されたか、存在しない作品IDです。";
+        let data = format!(r#"{{"error":true,"message":"{message}","body":[]}}"#);
+        let e = parse(404, &data).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::Pixiv(m)) if m == message));
+    }
+
+    #[test]
+    fn reports_status_without_json() {
+        for status in [404, 429, 503] {
+            let e = parse(status, "<html></html>").unwrap_err();
+            assert!(
+                matches!(e.downcast_ref(), Some(Error::Status(s)) if s.as_u16() == status),
+                "{status}"
+            );
+        }
+    }
+
+    #[test]
+    fn reports_empty_body() {
+        let e = parse(200, r#"{"error":false,"message":"","body":[]}"#).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::NoData)));
+    }
+
+    #[test]
+    fn parses_content_range() {
+        let cases = [
+            ("bytes 4-9/10", Some((Some(4), Some(10)))),
+            ("bytes */10", Some((None, Some(10)))),
+            ("bytes 4-9/*", Some((Some(4), None))),
+            ("items 4-9/10", None),
+            ("bytes 4-9", None),
+        ];
+        for (value, expected) in cases {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_RANGE, HeaderValue::from_static(value));
+            assert_eq!(content_range(&headers), expected, "{value}");
+        }
+        assert_eq!(content_range(&HeaderMap::new()), None);
+    }
+
+    /// Answers a single request with the given response, returning the request head
+    async fn serve_once(response: &'static str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/12345_ugoira600x600.zip",
+            listener.local_addr().unwrap()
+        );
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap().to_lowercase()
+        });
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn resumes_truncated_part() {
+        let (url, server) = serve_once(
+            "HTTP/1.1 206 Partial Content\r\n\
+             Content-Range: bytes 4-9/10\r\n\
+             Content-Length: 6\r\n\r\n\
+             456789",
+        )
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("12345_ugoira600x600.zip.part");
+        std::fs::write(&path, "0123").unwrap();
+
+        let result = Client::new().unwrap().download_to(&url, &path).await;
+        assert!(matches!(result, Ok(())));
+        assert!(server.await.unwrap().contains("range: bytes=4-\r\n"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"0123456789");
+    }
+
+    #[tokio::test]
+    async fn restarts_mismatched_part() {
+        let (url, server) = serve_once(
+            "HTTP/1.1 206 Partial Content\r\n\
+             Content-Range: bytes 0-9/10\r\n\
+             Content-Length: 10\r\n\r\n\
+             0123456789",
+        )
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("12345_ugoira600x600.zip.part");
+        std::fs::write(&path, "0123").unwrap();
+
+        let result = Client::new().unwrap().download_to(&url, &path).await;
+        assert!(matches!(result, Err(Attempt::Retry(..))));
+        server.await.unwrap();
+        assert!(!path.exists());
+    }
 }
@@ -1,9 +1,12 @@
 use std::fmt;
 
+use reqwest::StatusCode;
+
 #[derive(Debug)]
 pub enum Error {
     Pixiv(String),
     NoData,
+    Status(StatusCode),
 }
 
 impl std::error::Error for Error {}
@@ -13,6 +16,7 @@ impl fmt::Display for Error {
         match self {
             Error::Pixiv(message) => write!(f, "{}", message),
             Error::NoData => write!(f, "response has no data"),
+            Error::Status(status) => write!(f, "server responded with {status}"),
         }
     }
 }
@@ -1,6 +1,10 @@
 mod client;
 pub mod error;
+mod rate_limit;
 mod response;
+mod retry;
 pub mod types;
 
 pub use client::INSTANCE as CLIENT;
+pub use client::PROFILE_ILLUSTS_BATCH;
+pub use retry::RetryPolicy;
@@ -0,0 +1,60 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Token bucket, refilled at `rate` tokens per second up to a second worth of them
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Tokens per second, `None` for no limit
+    rate: Option<f64>,
+    /// May go negative, when requests are waiting for their turn
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<f64>) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate.unwrap_or_default().max(1.0),
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn set_rate(&self, rate: Option<f64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = rate;
+        bucket.tokens = rate.unwrap_or_default().max(1.0);
+        bucket.updated = Instant::now();
+    }
+
+    /// Waits until a request may be sent
+    pub async fn acquire(&self) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let rate = match bucket.rate {
+                Some(rate) => rate,
+                None => return,
+            };
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate.max(1.0));
+            bucket.updated = now;
+
+            // Reserve the token now, so that the waiting requests are let through in order
+            bucket.tokens -= 1.0;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        };
+
+        tokio::time::sleep(wait).await;
+    }
+}
@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use chrono::DateTime;
+use chrono::Utc;
+use rand::Rng;
+use reqwest::header::HeaderMap;
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every next one
+    pub base_delay: Duration,
+    /// Upper bound of random delay added to every retry
+    pub jitter: Duration,
+    /// Longest wait before a retry, even if `Retry-After` asks for more
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            jitter: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the given retry, `Retry-After` header taking precedence if the server sent one
+    pub fn delay(&self, attempt: u32, headers: Option<&HeaderMap>) -> Duration {
+        if let Some(retry_after) = headers.and_then(|headers| retry_after(headers, Utc::now())) {
+            return retry_after.min(self.max_delay);
+        }
+
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=self.jitter);
+
+        (backoff + jitter).min(self.max_delay)
+    }
+}
+
+/// Whether the request may succeed if sent again
+pub fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+pub fn is_transient_error(e: &reqwest::Error) -> bool {
+    e.is_connect() || e.is_timeout() || e.is_request() || e.is_body()
+}
+
+/// `Retry-After` in either seconds or HTTP date, a date in the past meaning no delay
+fn retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::TimeZone;
+    use chrono::Utc;
+    use reqwest::header::HeaderMap;
+    use reqwest::header::HeaderValue;
+    use reqwest::header::RETRY_AFTER;
+    use reqwest::StatusCode;
+
+    use super::is_transient_status;
+    use super::retry_after;
+    use super::RetryPolicy;
+
+    fn headers(retry_after: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static(retry_after));
+        headers
+    }
+
+    #[test]
+    fn classifies_statuses() {
+        for status in [429, 500, 502, 503, 504] {
+            let status = StatusCode::from_u16(status).unwrap();
+            assert!(is_transient_status(status), "{status}");
+        }
+        for status in [200, 400, 403, 404, 416] {
+            let status = StatusCode::from_u16(status).unwrap();
+            assert!(!is_transient_status(status), "{status}");
+        }
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        let now = Utc.with_ymd_and_hms(2022, 1, 2, 3, 4, 5).unwrap();
+        assert_eq!(
+            retry_after(&headers("120"), now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            retry_after(&headers("Sun, 02 Jan 2022 03:05:35 GMT"), now),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(
+            retry_after(&headers("Sun, 02 Jan 2022 03:00:00 GMT"), now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after(&headers("soon"), now), None);
+        assert_eq!(retry_after(&HeaderMap::new(), now), None);
+    }
+
+    #[test]
+    fn caps_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_secs(1),
+            jitter: Duration::ZERO,
+            max_delay: Duration::from_secs(60),
+        };
+        assert_eq!(
+            policy.delay(1, Some(&headers("86400"))),
+            Duration::from_secs(60)
+        );
+        assert_eq!(policy.delay(1, Some(&headers("5"))), Duration::from_secs(5));
+        assert_eq!(policy.delay(3, None), Duration::from_secs(4));
+        assert_eq!(policy.delay(9, None), Duration::from_secs(60));
+    }
+}
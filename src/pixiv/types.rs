@@ -1,5 +1,8 @@
 use std::collections::HashMap;
 
+use chrono::DateTime;
+use chrono::FixedOffset;
+use chrono::NaiveDate;
 use derive_more::Add;
 use derive_more::Display;
 use derive_more::From;
@@ -7,11 +10,12 @@ use derive_more::Into;
 use derive_more::Sub;
 use serde::Deserialize;
 use serde::Deserializer;
+use serde::Serialize;
 
-#[derive(Deserialize, From, Into, Display, Eq, PartialEq, Clone, Copy, Hash, Debug)]
+#[derive(Deserialize, Serialize, From, Into, Display, Eq, PartialEq, Clone, Copy, Hash, Debug)]
 pub struct IllustId(u32);
 
-#[derive(Deserialize, From, Into, Display, Eq, PartialEq, Clone, Copy, Hash, Debug)]
+#[derive(Deserialize, Serialize, From, Into, Display, Eq, PartialEq, Clone, Copy, Hash, Debug)]
 pub struct ProfileId(u32);
 
 #[derive(
@@ -54,10 +58,200 @@ where
         .collect::<Result<_, _>>()
 }
 
+#[derive(Deserialize, Debug)]
+pub struct ProfileIllusts {
+    #[serde(deserialize_with = "de_profile_works")]
+    pub works: Vec<IllustSummary>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IllustSummary {
+    #[serde(deserialize_with = "de_from_str")]
+    pub id: IllustId,
+    pub illust_type: IllustType,
+    #[serde(deserialize_with = "de_from_str")]
+    pub user_id: ProfileId,
+}
+
+fn de_profile_works<'de, D>(deserializer: D) -> Result<Vec<IllustSummary>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // Empty maps are sent as `[]`
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Works {
+        Empty(Vec<()>),
+        Works(HashMap<String, IllustSummary>),
+    }
+
+    match Works::deserialize(deserializer)? {
+        Works::Empty(_) => Ok(Vec::new()),
+        Works::Works(works) => Ok(works.into_values().collect()),
+    }
+}
+
+/// Page of a user's bookmarks, newest first
+#[derive(Deserialize, Debug)]
+pub struct Bookmarks {
+    pub works: Vec<IllustSummary>,
+    pub total: u32,
+}
+
+/// Page of new works by followed users, newest first
+#[derive(Deserialize, Debug)]
+pub struct FollowLatest {
+    pub page: FollowLatestPage,
+    pub thumbnails: Thumbnails,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FollowLatestPage {
+    pub is_last_page: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Thumbnails {
+    pub illust: Vec<IllustThumbnail>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IllustThumbnail {
+    #[serde(deserialize_with = "de_from_str")]
+    pub id: IllustId,
+    pub illust_type: IllustType,
+    pub create_date: DateTime<FixedOffset>,
+    #[serde(deserialize_with = "de_from_str")]
+    pub user_id: ProfileId,
+}
+
+/// Search for ugoira with the tag expression, e.g. `pixelart OR ドット絵`
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct SearchQuery {
+    pub word: String,
+    pub order: SearchOrder,
+    pub mode: SearchMode,
+    /// Only works posted on or after the date
+    pub since: Option<NaiveDate>,
+    /// Only works posted on or before the date
+    pub until: Option<NaiveDate>,
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum SearchOrder {
+    Newest,
+    Oldest,
+    Popular,
+}
+
+impl SearchOrder {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SearchOrder::Newest => "date_d",
+            SearchOrder::Oldest => "date",
+            SearchOrder::Popular => "popular_d",
+        }
+    }
+
+    pub fn from_query(s: &str) -> Option<Self> {
+        match s {
+            "date_d" => Some(SearchOrder::Newest),
+            "date" => Some(SearchOrder::Oldest),
+            "popular_d" => Some(SearchOrder::Popular),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum SearchMode {
+    All,
+    Safe,
+    R18,
+}
+
+impl SearchMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SearchMode::All => "all",
+            SearchMode::Safe => "safe",
+            SearchMode::R18 => "r18",
+        }
+    }
+
+    pub fn from_query(s: &str) -> Option<Self> {
+        match s {
+            "all" => Some(SearchMode::All),
+            "safe" => Some(SearchMode::Safe),
+            "r18" => Some(SearchMode::R18),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchResults {
+    pub illust: SearchPage,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchPage {
+    #[serde(deserialize_with = "de_search_data")]
+    pub data: Vec<IllustThumbnail>,
+    pub last_page: u32,
+}
+
+fn de_search_data<'de, D>(deserializer: D) -> Result<Vec<IllustThumbnail>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // Results are interleaved with ad placeholders
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Entry {
+        Illust(IllustThumbnail),
+        Ad(serde::de::IgnoredAny),
+    }
+
+    let entries = Vec::<Entry>::deserialize(deserializer)?;
+    Ok(entries
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Entry::Illust(illust) => Some(illust),
+            Entry::Ad(_) => None,
+        })
+        .collect())
+}
+
+/// Visibility of bookmarks, as the `rest` query parameter calls it
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum BookmarkRest {
+    /// Public bookmarks
+    Show,
+    /// Private bookmarks, only visible to their owner
+    Hide,
+}
+
+impl BookmarkRest {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BookmarkRest::Show => "show",
+            BookmarkRest::Hide => "hide",
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all(deserialize = "camelCase", serialize = "snake_case"))]
 pub struct UgoiraMeta {
+    pub src: String,
     pub original_src: String,
+    #[serde(rename(deserialize = "mime_type"))]
+    pub mime_type: String,
     pub frames: Vec<UgoiraMetaFrame>,
 }
 
@@ -66,3 +260,107 @@ pub struct UgoiraMetaFrame {
     pub file: String,
     pub delay: UgoiraDelay,
 }
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(try_from = "u8")]
+pub enum IllustType {
+    Illust,
+    Manga,
+    Ugoira,
+}
+
+impl TryFrom<u8> for IllustType {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(IllustType::Illust),
+            1 => Ok(IllustType::Manga),
+            2 => Ok(IllustType::Ugoira),
+            _ => Err(format!("unknown illustType {value}")),
+        }
+    }
+}
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(try_from = "u8")]
+pub enum XRestrict {
+    AllAges,
+    R18,
+    R18G,
+}
+
+impl TryFrom<u8> for XRestrict {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(XRestrict::AllAges),
+            1 => Ok(XRestrict::R18),
+            2 => Ok(XRestrict::R18G),
+            _ => Err(format!("unknown xRestrict {value}")),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IllustDetails {
+    #[serde(deserialize_with = "de_from_str")]
+    pub illust_id: IllustId,
+    pub illust_title: String,
+    pub illust_type: IllustType,
+    pub create_date: DateTime<FixedOffset>,
+    pub upload_date: DateTime<FixedOffset>,
+    #[serde(deserialize_with = "de_from_str")]
+    pub user_id: ProfileId,
+    pub user_name: String,
+    pub width: u32,
+    pub height: u32,
+    pub x_restrict: XRestrict,
+    #[serde(deserialize_with = "de_illust_tags")]
+    pub tags: Vec<String>,
+}
+
+/// Deserializes ids that pixiv sends as strings,
+/// or as numbers in the case of deleted works in bookmarks
+fn de_from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: From<u32>,
+{
+    use serde::de::Error;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Id {
+        Number(u32),
+        String(String),
+    }
+
+    match Id::deserialize(deserializer)? {
+        Id::Number(o) => Ok(T::from(o)),
+        Id::String(s) => match s.parse::<u32>() {
+            Ok(o) => Ok(T::from(o)),
+            Err(e) => Err(D::Error::custom(e)),
+        },
+    }
+}
+
+fn de_illust_tags<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Tags {
+        tags: Vec<Tag>,
+    }
+
+    #[derive(Deserialize)]
+    struct Tag {
+        tag: String,
+    }
+
+    let Tags { tags } = Tags::deserialize(deserializer)?;
+    Ok(tags.into_iter().map(|Tag { tag }| tag).collect())
+}
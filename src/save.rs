@@ -1,13 +1,36 @@
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
 
 use anyhow::Result;
 use bytes::Bytes;
+use chrono::DateTime;
+use chrono::FixedOffset;
 use derive_more::Constructor;
+use derive_more::From;
 use futures::stream::BoxStream;
+use par_stream::ParParams;
 use par_stream::ParStreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tempfile::NamedTempFile;
 use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+use zip::ZipArchive;
+use zip::ZipWriter;
 
-use crate::pixiv::types::IllustId;
+use crate::ugoira::UgoiraArchive;
+use crate::ugoira::ANIMATION_JSON;
+
+/// Chunk size of reading saved files back for hashing
+const HASH_BUF_SIZE: usize = 64 * 1024;
 
 pub trait SaveParams {
     fn get_extension(&self) -> &'static str;
@@ -17,6 +40,15 @@ pub trait SaveParams {
 pub struct WebmParams;
 #[derive(Clone, Copy)]
 pub struct GifParams;
+#[derive(Clone, Copy)]
+pub struct ApngParams;
+#[derive(Clone, Copy)]
+pub struct WebpParams;
+#[derive(Clone, Copy)]
+pub struct Mp4Params;
+/// Ugoira zip as it was downloaded, with `animation.json` added
+#[derive(Clone, Copy)]
+pub struct ZipParams;
 
 impl SaveParams for WebmParams {
     fn get_extension(&self) -> &'static str {
@@ -30,42 +62,207 @@ impl SaveParams for GifParams {
     }
 }
 
+impl SaveParams for ApngParams {
+    fn get_extension(&self) -> &'static str {
+        "png"
+    }
+}
+
+impl SaveParams for WebpParams {
+    fn get_extension(&self) -> &'static str {
+        "webp"
+    }
+}
+
+impl SaveParams for Mp4Params {
+    fn get_extension(&self) -> &'static str {
+        "mp4"
+    }
+}
+
+impl SaveParams for ZipParams {
+    fn get_extension(&self) -> &'static str {
+        "zip"
+    }
+}
+
+/// What to do with illustrations whose files exist already
+#[derive(Clone, Copy, Debug)]
+pub enum ExistingPolicy {
+    Overwrite,
+    Skip,
+    /// Skip, unless the illustration was updated after the files were written
+    UpdateIfChanged,
+}
+
+/// Contents of a saved file
+#[derive(From)]
+pub enum SaveData {
+    Bytes(Bytes),
+    /// Entries of the archive copied as they are, so they aren't read into memory,
+    /// and the frame list stored as `animation.json` inside like Honeyview expects
+    Archive {
+        archive: Arc<UgoiraArchive>,
+        animation_json: Bytes,
+    },
+}
+
+/// File written by the saver
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SavedFile {
+    pub path: PathBuf,
+    /// Hex digest of the contents
+    pub sha256: String,
+}
+
 #[derive(Constructor)]
 struct UgoiraSaver {
-    illust_id: IllustId,
-    data: Bytes,
-    dir: PathBuf,
+    data: SaveData,
+    path: PathBuf,
     params: Box<dyn SaveParams + Send>,
 }
 
 impl UgoiraSaver {
-    async fn into(self) -> Result<File> {
-        let file_ext = self.params.get_extension();
-        let filename = format!("{i}.{file_ext}", i = self.illust_id);
-        let filepath = self.dir.join(filename);
+    async fn into(self) -> Result<SavedFile> {
+        let filepath = file_path(&self.path, self.params.as_ref());
 
-        let mut file = File::create(filepath).await?;
-        tokio::io::copy(&mut self.data.as_ref(), &mut file).await?;
-        file.sync_all().await?;
+        if let Some(parent) = filepath.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
 
-        Ok(file)
+        match self.data {
+            SaveData::Bytes(data) => {
+                let mut file = File::create(&filepath).await?;
+                tokio::io::copy(&mut data.as_ref(), &mut file).await?;
+                file.sync_all().await?;
+            }
+            SaveData::Archive {
+                archive,
+                animation_json,
+            } => {
+                let filepath = filepath.clone();
+                tokio::task::spawn_blocking(move || {
+                    write_archive(archive.path(), &animation_json, &filepath)
+                })
+                .await??;
+            }
+        }
+
+        let sha256 = sha256_file(&filepath).await?;
+        Ok(SavedFile {
+            path: filepath,
+            sha256,
+        })
     }
 }
 
+/// Appends the extension to the rendered file name, keeping any dots it already has
+pub fn file_path(path: &Path, params: &(dyn SaveParams + Send)) -> PathBuf {
+    let mut filepath = path.as_os_str().to_owned();
+    filepath.push(".");
+    filepath.push(params.get_extension());
+    PathBuf::from(filepath)
+}
+
+/// Whether every file of the illustration exists, and is up to date according to the policy
+pub async fn is_saved(
+    paths: &[PathBuf],
+    policy: ExistingPolicy,
+    updated: Option<DateTime<FixedOffset>>,
+) -> Result<bool> {
+    if let ExistingPolicy::Overwrite = policy {
+        return Ok(false);
+    }
+
+    for path in paths {
+        let metadata = match tokio::fs::metadata(path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+
+        if let (ExistingPolicy::UpdateIfChanged, Some(updated)) = (policy, updated) {
+            if metadata.modified()? < SystemTime::from(updated) {
+                return Ok(false);
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+async fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; HASH_BUF_SIZE];
+    loop {
+        let len = file.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Copies the zip with `animation_json` in place of any frame list it has.
+/// The copy is written next to `path` and renamed over it,
+/// so a local zip saved over itself isn't truncated while being read
+fn write_archive(source: &Path, animation_json: &[u8], path: &Path) -> Result<()> {
+    let mut zip_archive = ZipArchive::new(std::fs::File::open(source)?)?;
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut file = NamedTempFile::new_in(dir)?;
+    {
+        let mut zip_writer = ZipWriter::new(file.as_file_mut());
+        for i in 0..zip_archive.len() {
+            let zip_file = zip_archive.by_index_raw(i)?;
+            if zip_file.name() != ANIMATION_JSON {
+                zip_writer.raw_copy_file(zip_file)?;
+            }
+        }
+
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        zip_writer.start_file(ANIMATION_JSON, options)?;
+        zip_writer.write_all(animation_json)?;
+        zip_writer.finish()?;
+    }
+
+    file.as_file().sync_all()?;
+    file.persist(path)?;
+    Ok(())
+}
+
+pub type SaveFile = (SaveData, Box<dyn SaveParams + Send>);
+
 #[derive(Constructor)]
-pub struct StreamUgoiraSaver {
-    data_stream: BoxStream<'static, (IllustId, Bytes, PathBuf, Box<dyn SaveParams + Send>)>,
-}
-
-impl StreamUgoiraSaver {
-    pub fn into_stream(self) -> BoxStream<'static, (IllustId, Result<File>)> {
-        Box::pin(self.data_stream.par_then_unordered(
-            None,
-            |(illust_id, data, dir, params)| async move {
-                let saver = UgoiraSaver::new(illust_id, data, dir, params);
-                let file = saver.into().await;
-                (illust_id, file)
-            },
-        ))
+pub struct StreamUgoiraSaver<I>
+where
+    I: 'static,
+{
+    data_stream: BoxStream<'static, (I, Vec<SaveFile>, PathBuf)>,
+    params: ParParams,
+}
+
+impl<I> StreamUgoiraSaver<I>
+where
+    I: Send,
+{
+    pub fn into_stream(self) -> BoxStream<'static, (I, Result<Vec<SavedFile>>)> {
+        Box::pin(
+            self.data_stream
+                .par_then_unordered(self.params, |(i, files, path)| async move {
+                    let mut result = Vec::with_capacity(files.len());
+                    for (data, params) in files {
+                        let saver = UgoiraSaver::new(data, path.clone(), params);
+                        match saver.into().await {
+                            Ok(file) => result.push(file),
+                            Err(e) => return (i, Err(e)),
+                        }
+                    }
+                    (i, Ok(result))
+                }),
+        )
     }
 }
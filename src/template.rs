@@ -0,0 +1,443 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::bail;
+use derive_more::Constructor;
+
+use crate::pixiv::types::IllustDetails;
+use crate::pixiv::types::IllustId;
+
+/// Longest file or directory name produced, in bytes, leaving room for the extension
+const MAX_COMPONENT_LEN: usize = 200;
+
+/// Names that can't be used for files on Windows, regardless of the extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Clone, Copy, Debug)]
+enum Field {
+    Id,
+    Title,
+    UserId,
+    UserName,
+    Date,
+    Tags,
+    Format,
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+    Separator,
+}
+
+/// File name template, e.g. `{user_name}/{date} {title} ({id})`.
+///
+/// Fields are written in braces, literal braces are escaped as `{{` and `}}`,
+/// and both `/` and `\` start a subdirectory. The extension is appended on save.
+#[derive(Clone, Debug)]
+pub struct FilenameTemplate {
+    segments: Vec<Segment>,
+}
+
+/// Values of the template fields.
+/// Without illustration details, e.g. for local zips, only `{id}` and `{format}` are known
+/// and the rest are rendered as empty
+#[derive(Constructor)]
+pub struct FilenameFields<'a> {
+    illust_id: IllustId,
+    format: &'a str,
+    details: Option<&'a IllustDetails>,
+}
+
+impl Field {
+    /// Whether the value is free-form text that can be cut to fit the name,
+    /// unlike ids and dates that tell files apart
+    fn is_shortenable(self) -> bool {
+        matches!(self, Field::Title | Field::UserName | Field::Tags)
+    }
+}
+
+impl FromStr for Field {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let field = match s {
+            "id" => Field::Id,
+            "title" => Field::Title,
+            "user_id" => Field::UserId,
+            "user_name" => Field::UserName,
+            "date" => Field::Date,
+            "tags" => Field::Tags,
+            "format" => Field::Format,
+            _ => bail!("unknown filename template field {{{s}}}"),
+        };
+
+        Ok(field)
+    }
+}
+
+impl FromStr for FilenameTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = match rest.find('}') {
+                        Some(end) => end,
+                        None => bail!("unclosed {{ in filename template"),
+                    };
+                    let field = rest[..end].parse()?;
+                    chars = rest[end + 1..].chars();
+
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    segments.push(Segment::Field(field));
+                }
+                '}' => bail!("unmatched }} in filename template"),
+                '/' | '\\' => {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    segments.push(Segment::Separator);
+                }
+                c => literal.push(c),
+            }
+        }
+        segments.push(Segment::Literal(literal));
+
+        if !segments.iter().any(|s| matches!(s, Segment::Field(_))) {
+            bail!("filename template has no fields, every file would get the same name");
+        }
+
+        Ok(Self { segments })
+    }
+}
+
+impl FilenameTemplate {
+    /// Whether any field comes from the illustration details
+    pub fn needs_details(&self) -> bool {
+        self.segments.iter().any(|segment| {
+            matches!(
+                segment,
+                Segment::Field(
+                    Field::Title | Field::UserId | Field::UserName | Field::Date | Field::Tags
+                )
+            )
+        })
+    }
+
+    /// Renders the path of saved files, relative to the output directory and without extension
+    pub fn render(&self, fields: &FilenameFields) -> PathBuf {
+        let mut path = PathBuf::new();
+        // Rendered parts of the current component, and whether each can be shortened
+        let mut component = Vec::<(String, bool)>::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => component.push((literal.clone(), false)),
+                Segment::Field(field) => {
+                    component.push((render_field(*field, fields), field.is_shortenable()))
+                }
+                Segment::Separator => {
+                    push_component(&mut path, &mut component);
+                    component.clear();
+                }
+            }
+        }
+        push_component(&mut path, &mut component);
+
+        if path.as_os_str().is_empty() {
+            path.push(fields.illust_id.to_string());
+        }
+
+        path
+    }
+}
+
+fn render_field(field: Field, fields: &FilenameFields) -> String {
+    let details = fields.details;
+    let value = match field {
+        Field::Id => fields.illust_id.to_string(),
+        Field::Format => fields.format.to_string(),
+        Field::Title => details.map(|d| d.illust_title.clone()).unwrap_or_default(),
+        Field::UserId => details.map(|d| d.user_id.to_string()).unwrap_or_default(),
+        Field::UserName => details.map(|d| d.user_name.clone()).unwrap_or_default(),
+        Field::Date => details
+            .map(|d| d.create_date.format("%Y-%m-%d").to_string())
+            .unwrap_or_default(),
+        Field::Tags => details.map(|d| d.tags.join(" ")).unwrap_or_default(),
+    };
+
+    // Values must never introduce directories of their own
+    value.replace(['/', '\\'], "_")
+}
+
+fn push_component(path: &mut PathBuf, parts: &mut [(String, bool)]) {
+    fit_component(parts);
+    let component = parts
+        .iter()
+        .map(|(part, _)| part.as_str())
+        .collect::<String>();
+
+    let component = sanitize(&component);
+    if !component.is_empty() {
+        path.push(component);
+    }
+}
+
+/// Shortens the free-form values so that the component fits into [`MAX_COMPONENT_LEN`],
+/// keeping literals and the other fields whole, so that names stay unique
+fn fit_component(parts: &mut [(String, bool)]) {
+    let len = parts.iter().map(|(part, _)| part.len()).sum::<usize>();
+    if len <= MAX_COMPONENT_LEN {
+        return;
+    }
+
+    let fixed = parts
+        .iter()
+        .filter(|(_, shortenable)| !shortenable)
+        .map(|(part, _)| part.len())
+        .sum::<usize>();
+    let mut room = MAX_COMPONENT_LEN.saturating_sub(fixed);
+
+    // Shortest values first, so that the room they don't need goes to the longer ones
+    let mut shortenable = (0..parts.len()).filter(|i| parts[*i].1).collect::<Vec<_>>();
+    shortenable.sort_by_key(|i| parts[*i].0.len());
+
+    let mut left = shortenable.len();
+    for i in shortenable {
+        let part = &mut parts[i].0;
+        let len = truncate(part, room / left).len();
+        part.truncate(len);
+        room -= len;
+        left -= 1;
+    }
+}
+
+/// Makes a file name valid on every platform
+fn sanitize(name: &str) -> String {
+    let mut sanitized = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => sanitized.push('_'),
+            c if c.is_control() => sanitized.push('_'),
+            c => sanitized.push(c),
+        }
+    }
+
+    // Windows drops trailing dots and spaces, which also takes care of `.` and `..`
+    let mut sanitized = truncate(sanitized.trim(), MAX_COMPONENT_LEN)
+        .trim_end_matches(['.', ' '])
+        .to_string();
+
+    let stem = sanitized.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        sanitized = format!("_{sanitized}");
+    }
+
+    sanitized
+}
+
+/// Cuts the string to at most `len` bytes on a char boundary
+fn truncate(s: &str, len: usize) -> &str {
+    if s.len() <= len {
+        return s;
+    }
+
+    let end = (0..=len)
+        .rev()
+        .find(|i| s.is_char_boundary(*i))
+        .unwrap_or(0);
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use serde_json::json;
+
+    use super::sanitize;
+    use super::FilenameFields;
+    use super::FilenameTemplate;
+    use super::MAX_COMPONENT_LEN;
+    use crate::pixiv::types::IllustDetails;
+    use crate::pixiv::types::IllustId;
+
+    fn details(title: &str, user_name: &str, tags: &[&str]) -> IllustDetails {
+        let tags = tags
+            .iter()
+            .map(|tag| json!({ "tag": tag }))
+            .collect::<Vec<_>>();
+        serde_json::from_value(json!({
+            "illustId": "12345",
+            "illustTitle": title,
+            "illustType": 2,
+            "createDate": "2022-01-02T03:04:05+09:00",
+            "uploadDate": "2022-01-03T03:04:05+09:00",
+            "userId": "678",
+            "userName": user_name,
+            "width": 600,
+            "height": 600,
+            "xRestrict": 0,
+            "tags": { "tags": tags },
+        }))
+        .unwrap()
+    }
+
+    fn render(template: &str, details: Option<&IllustDetails>) -> PathBuf {
+        let template = template.parse::<FilenameTemplate>().unwrap();
+        let fields = FilenameFields::new(IllustId::from(12345), "gif", details);
+        template.render(&fields)
+    }
+
+    #[test]
+    fn renders_every_field() {
+        let details = details("Title", "User", &["tag1", "tag2"]);
+        let path = render(
+            "{id} {title} {user_id} {user_name} {date} {tags} {format}",
+            Some(&details),
+        );
+        assert_eq!(
+            path,
+            PathBuf::from("12345 Title 678 User 2022-01-02 tag1 tag2 gif")
+        );
+    }
+
+    #[test]
+    fn renders_missing_details_as_empty() {
+        assert_eq!(render("{id}{title}", None), PathBuf::from("12345"));
+        assert_eq!(render("{user_name}/{id}", None), PathBuf::from("12345"));
+        // Nothing left of the path at all
+        assert_eq!(render("{title}", None), PathBuf::from("12345"));
+    }
+
+    #[test]
+    fn needs_details_only_for_their_fields() {
+        for (template, needs_details) in [
+            ("{id}", false),
+            ("{format}/{id}", false),
+            ("{{title}} {id}", false),
+            ("{title}", true),
+            ("{user_id}/{id}", true),
+            ("{id} {tags}", true),
+        ] {
+            let template = template.parse::<FilenameTemplate>().unwrap();
+            assert_eq!(template.needs_details(), needs_details, "{template:?}");
+        }
+    }
+
+    #[test]
+    fn splits_directories() {
+        let details = details("Title", "User", &[]);
+        assert_eq!(
+            render("{user_name}/{date}\\{id}", Some(&details)),
+            ["User", "2022-01-02", "12345"].iter().collect::<PathBuf>()
+        );
+    }
+
+    #[test]
+    fn unescapes_braces() {
+        assert_eq!(render("{{{id}}}", None), PathBuf::from("{12345}"));
+        assert_eq!(render("{{id}} {id}", None), PathBuf::from("{id} 12345"));
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for template in ["{unknown}", "{id", "id}", "{id} }", "no fields", "{{id}}"] {
+            assert!(
+                template.parse::<FilenameTemplate>().is_err(),
+                "{template:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_separators_out_of_values() {
+        let details = details("a/b\\c", "../..", &["x/y"]);
+        assert_eq!(
+            render("{user_name}/{title} {tags}", Some(&details)),
+            [".._", "a_b_c x_y"].iter().collect::<PathBuf>()
+        );
+    }
+
+    #[test]
+    fn sanitizes_names() {
+        assert_eq!(sanitize("a<b>c:d\"e|f?g*h"), "a_b_c_d_e_f_g_h");
+        assert_eq!(sanitize("tab\there"), "tab_here");
+        assert_eq!(sanitize("  name. . "), "name");
+        assert_eq!(sanitize(".."), "");
+        assert_eq!(sanitize("."), "");
+    }
+
+    #[test]
+    fn escapes_reserved_names() {
+        assert_eq!(sanitize("CON"), "_CON");
+        assert_eq!(sanitize("con"), "_con");
+        assert_eq!(sanitize("nul.tar"), "_nul.tar");
+        assert_eq!(sanitize("LPT9."), "_LPT9");
+        assert_eq!(sanitize("CONSOLE"), "CONSOLE");
+        assert_eq!(sanitize("COM10"), "COM10");
+    }
+
+    #[test]
+    fn truncates_on_char_boundaries() {
+        // 3 bytes each, so the limit falls inside of a char
+        let title = "あ".repeat(100);
+        let name = sanitize(&title);
+        assert!(name.len() <= MAX_COMPONENT_LEN);
+        assert_eq!(name, "あ".repeat(MAX_COMPONENT_LEN / 3));
+
+        let details = details(&title, "User", &[]);
+        let path = render("{title} ({id})", Some(&details));
+        let name = path.to_str().unwrap();
+        assert!(name.len() <= MAX_COMPONENT_LEN);
+        assert!(name.ends_with(" (12345)"), "{name}");
+        assert!(name.trim_end_matches(" (12345)").chars().all(|c| c == 'あ'));
+    }
+
+    #[test]
+    fn shortens_only_free_form_fields() {
+        let title = "a".repeat(300);
+        let tags = ["b".repeat(300), "short".to_string()];
+        let tags = tags.iter().map(String::as_str).collect::<Vec<_>>();
+        let long = details(&title, "User", &tags);
+
+        let path = render("{user_id} {title} [{tags}] {date} ({id})", Some(&long));
+        let name = path.to_str().unwrap();
+        assert_eq!(name.len(), MAX_COMPONENT_LEN);
+        assert!(name.starts_with("678 a"), "{name}");
+        assert!(name.ends_with("] 2022-01-02 (12345)"), "{name}");
+
+        // Room that the short value doesn't need is left to the long one
+        let short = details(&title, "User", &["tag"]);
+        let path = render("{title} [{tags}] ({id})", Some(&short));
+        let name = path.to_str().unwrap();
+        assert_eq!(name.len(), MAX_COMPONENT_LEN);
+        assert!(name.ends_with(" [tag] (12345)"), "{name}");
+    }
+
+    #[test]
+    fn trims_after_truncating() {
+        let name = format!("{} .", "a".repeat(MAX_COMPONENT_LEN - 1));
+        assert_eq!(sanitize(&name), "a".repeat(MAX_COMPONENT_LEN - 1));
+    }
+}
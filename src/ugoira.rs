@@ -1,80 +1,413 @@
-use std::io::Cursor;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
 
+use anyhow::bail;
+use anyhow::Context;
 use anyhow::Result;
 use bytes::BufMut;
 use bytes::Bytes;
 use bytes::BytesMut;
 use derive_more::Constructor;
 use futures::stream::BoxStream;
+use par_stream::ParParams;
 use par_stream::ParStreamExt;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::json;
+use tempfile::TempPath;
 use zip::ZipArchive;
 
 use crate::pixiv::types::IllustId;
 use crate::pixiv::types::UgoiraDelay;
 use crate::pixiv::types::UgoiraMetaFrame;
 use crate::pixiv::CLIENT as PIXIV_CLIENT;
+use crate::util::JobLimit;
+
+/// Name of the frame list that some tools put inside ugoira zips
+pub const ANIMATION_JSON: &str = "animation.json";
 
 #[derive(Constructor)]
 struct UgoiraDataProvider {
     illust_id: IllustId,
+    /// Where downloads are kept until encoded, partial ones as `{name}.part`
+    spool_dir: PathBuf,
+}
+
+/// Ugoira zip on disk, either a spooled download or a local file
+#[derive(Debug)]
+pub struct UgoiraArchive {
+    path: PathBuf,
+    /// Removes the spooled download once the archive is dropped
+    _spool: Option<TempPath>,
+    zip_archive: Mutex<ZipArchive<std::fs::File>>,
+}
+
+impl UgoiraArchive {
+    /// Opens the zip and reads its central directory on a blocking thread
+    async fn open(path: PathBuf, spool: Option<TempPath>) -> Result<Self> {
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::File::open(&path)?;
+            let zip_archive = ZipArchive::new(file)?;
+
+            Ok(Self {
+                path,
+                _spool: spool,
+                zip_archive: Mutex::new(zip_archive),
+            })
+        })
+        .await?
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn file_names(&self) -> Vec<String> {
+        let zip_archive = self.zip_archive.lock().unwrap();
+        zip_archive.file_names().map(str::to_string).collect()
+    }
+
+    /// Inflates the entry, blocking until it's read
+    fn read(&self, name: &str) -> Result<Bytes> {
+        let mut zip_archive = self.zip_archive.lock().unwrap();
+        let mut zip_file = zip_archive.by_name(name)?;
+        let filesize: usize = zip_file.size().try_into()?;
+
+        let mut buf_writer = BytesMut::with_capacity(filesize).writer();
+        std::io::copy(&mut zip_file, &mut buf_writer)?;
+        Ok(buf_writer.into_inner().freeze())
+    }
 }
 
+/// Frame of the archive, read on demand so that whole animations aren't held in memory
 #[derive(Debug)]
 pub struct UgoiraFrame {
     pub file: String,
-    pub data: Bytes,
     pub delay: UgoiraDelay,
+    source: FrameSource,
+}
+
+#[derive(Debug)]
+enum FrameSource {
+    Archive(Arc<UgoiraArchive>),
+    /// Frame rewritten by validation, kept in memory
+    Repaired(Bytes),
+}
+
+impl UgoiraFrame {
+    pub fn repaired(file: String, delay: UgoiraDelay, data: Bytes) -> Self {
+        Self {
+            file,
+            delay,
+            source: FrameSource::Repaired(data),
+        }
+    }
+
+    /// Reads the frame on a blocking thread, so that it can be awaited from async code
+    pub async fn data(&self) -> Result<Bytes> {
+        let archive = match &self.source {
+            FrameSource::Archive(archive) => Arc::clone(archive),
+            FrameSource::Repaired(data) => return Ok(data.clone()),
+        };
+
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || archive.read(&file)).await?
+    }
+
+    /// Reads the frame on the current thread, for code already off the async runtime
+    pub fn blocking_data(&self) -> Result<Bytes> {
+        match &self.source {
+            FrameSource::Archive(archive) => archive.read(&self.file),
+            FrameSource::Repaired(data) => Ok(data.clone()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Ugoira {
+    /// Zip the frames are read from, as it was downloaded
+    pub archive: Arc<UgoiraArchive>,
+    pub frames: Vec<UgoiraFrame>,
+    pub src: Option<String>,
+    pub original_src: Option<String>,
+    pub mime_type: Option<String>,
+}
+
+impl Ugoira {
+    /// Frame list in the `animation.json` layout that Honeyview and gallery-dl understand
+    pub fn animation_json(&self) -> Bytes {
+        let frames = self
+            .frames
+            .iter()
+            .map(|frame| json!({ "file": frame.file, "delay": u32::from(frame.delay) }))
+            .collect::<Vec<_>>();
+
+        let animation = json!({
+            "ugokuIllustData": {
+                "src": self.src,
+                "originalSrc": self.original_src,
+                "mime_type": self.mime_type,
+                "frames": frames,
+            }
+        });
+
+        Bytes::from(animation.to_string())
+    }
+}
+
+#[cfg(test)]
+impl Ugoira {
+    /// Ugoira of the zip on disk with the given frame list, without any metadata
+    pub async fn with_frames(path: PathBuf, frames: Vec<UgoiraMetaFrame>) -> Result<Self> {
+        let archive = Arc::new(UgoiraArchive::open(path, None).await?);
+        let frames = list_frames(&archive, frames);
+
+        Ok(Self {
+            archive,
+            frames,
+            src: None,
+            original_src: None,
+            mime_type: None,
+        })
+    }
 }
 
 impl UgoiraDataProvider {
-    pub async fn into(self) -> Result<Vec<UgoiraFrame>> {
+    pub async fn into(self) -> Result<Ugoira> {
         let client = &PIXIV_CLIENT;
 
         let meta = client.ugoira_meta(self.illust_id).await?;
-        let data = client.download_ugoira(&meta).await?;
 
-        let mut zip_archive = ZipArchive::new(Cursor::new(data))?;
+        let url = Url::parse(&meta.original_src)?;
+        let name = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|name| !name.is_empty())
+            .map_or_else(|| format!("{}.zip", self.illust_id), str::to_string);
+        let path = self.spool_dir.join(&name);
+        let part_path = self.spool_dir.join(format!("{name}.part"));
 
-        let result = meta
-            .frames
-            .into_iter()
-            .map(|UgoiraMetaFrame { file, delay, .. }| {
-                let mut zip_file = zip_archive.by_name(&file)?;
-                let filesize: usize = zip_file.size().try_into()?;
-
-                let data = {
-                    let mut buf_writer = BytesMut::with_capacity(filesize).writer();
-                    std::io::copy(&mut zip_file, &mut buf_writer)?;
-                    buf_writer.into_inner().freeze()
-                };
-
-                Ok(UgoiraFrame { file, data, delay })
-            })
-            .collect::<Result<_>>()?;
+        tokio::fs::create_dir_all(&self.spool_dir).await?;
+        client
+            .download_ugoira(self.illust_id, &meta, &part_path)
+            .await?;
+
+        // A truncated part can't be resumed, so the next run starts over
+        let check_path = part_path.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || check_archive(&check_path)).await? {
+            tokio::fs::remove_file(&part_path).await?;
+            return Err(e.context(format!("downloaded archive {name} is corrupt")));
+        }
+        tokio::fs::rename(&part_path, &path).await?;
+
+        let spool = TempPath::try_from_path(&path)?;
+        let archive = Arc::new(UgoiraArchive::open(path, Some(spool)).await?);
+        let frames = list_frames(&archive, meta.frames);
 
-        Ok(result)
+        Ok(Ugoira {
+            archive,
+            frames,
+            src: Some(meta.src),
+            original_src: Some(meta.original_src),
+            mime_type: Some(meta.mime_type),
+        })
     }
 }
 
+/// Ugoira zip previously saved to disk
+#[derive(Constructor)]
+struct LocalUgoiraDataProvider {
+    path: PathBuf,
+}
+
+/// Frame list of either `ugoira_meta` response, its body,
+/// or `animation.json` in the layout Honeyview understands
+/// or the bare list gallery-dl puts in the zips it archives
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LocalUgoiraMeta {
+    Response {
+        body: LocalUgoiraFrames,
+    },
+    Honeyview {
+        #[serde(rename = "ugokuIllustData")]
+        ugoku_illust_data: LocalUgoiraFrames,
+    },
+    Frames(LocalUgoiraFrames),
+    List(Vec<UgoiraMetaFrame>),
+}
+
+#[derive(Deserialize)]
+struct LocalUgoiraFrames {
+    src: Option<String>,
+    #[serde(rename = "originalSrc")]
+    original_src: Option<String>,
+    mime_type: Option<String>,
+    frames: Vec<UgoiraMetaFrame>,
+}
+
+impl LocalUgoiraDataProvider {
+    pub async fn into(self) -> Result<Ugoira> {
+        let archive = Arc::new(UgoiraArchive::open(self.path.clone(), None).await?);
+
+        let meta = match self.read_sidecar_meta().await? {
+            Some(meta) => meta,
+            None => {
+                let reader = Arc::clone(&archive);
+                let data = tokio::task::spawn_blocking(move || reader.read(ANIMATION_JSON))
+                    .await?
+                    .with_context(|| format!("no metadata found for {:?}", self.path))?;
+                serde_json::from_slice(&data)?
+            }
+        };
+
+        let meta = match meta {
+            LocalUgoiraMeta::Response { body } => body,
+            LocalUgoiraMeta::Honeyview { ugoku_illust_data } => ugoku_illust_data,
+            LocalUgoiraMeta::Frames(frames) => frames,
+            LocalUgoiraMeta::List(frames) => LocalUgoiraFrames {
+                src: None,
+                original_src: None,
+                mime_type: None,
+                frames,
+            },
+        };
+        let frames = list_frames(&archive, meta.frames);
+
+        Ok(Ugoira {
+            archive,
+            frames,
+            src: meta.src,
+            original_src: meta.original_src,
+            mime_type: meta.mime_type,
+        })
+    }
+
+    /// Looks for `{name}.json` and `{name}.zip.json` next to the zip
+    async fn read_sidecar_meta(&self) -> Result<Option<LocalUgoiraMeta>> {
+        let mut zip_json = self.path.clone().into_os_string();
+        zip_json.push(".json");
+
+        for path in [self.path.with_extension("json"), PathBuf::from(zip_json)] {
+            match tokio::fs::read(&path).await {
+                Ok(data) => return Ok(Some(serde_json::from_slice(&data)?)),
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Parses illustration id from the leading digits of file name,
+/// e.g. `12345678_ugoira1920x1080.zip`
+pub fn local_illust_id(path: &Path) -> Result<IllustId> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let digits = name
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(name.as_ref(), |end| &name[..end]);
+
+    match digits.parse::<u32>() {
+        Ok(o) => Ok(IllustId::from(o)),
+        Err(_) => bail!("can't find illustration id in file name {path:?}"),
+    }
+}
+
+/// Checks that the central directory is intact
+/// and that every entry it lists fits into the file
+fn check_archive(path: &Path) -> Result<()> {
+    let file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut zip_archive = ZipArchive::new(file)?;
+
+    for i in 0..zip_archive.len() {
+        let zip_file = zip_archive.by_index_raw(i)?;
+        if zip_file.data_start() + zip_file.compressed_size() > len {
+            bail!("entry {} is truncated", zip_file.name());
+        }
+    }
+
+    Ok(())
+}
+
+/// Frames of the metadata, read from the archive once needed
+fn list_frames(archive: &Arc<UgoiraArchive>, frames: Vec<UgoiraMetaFrame>) -> Vec<UgoiraFrame> {
+    frames
+        .into_iter()
+        .map(|UgoiraMetaFrame { file, delay, .. }| UgoiraFrame {
+            file,
+            delay,
+            source: FrameSource::Archive(Arc::clone(archive)),
+        })
+        .collect()
+}
+
 #[derive(Constructor)]
 pub struct StreamUgoiraDataProvider<I>
 where
     I: 'static,
 {
     illust_ids: BoxStream<'static, (I, IllustId)>,
+    spool_dir: PathBuf,
+    limit: JobLimit,
+    params: ParParams,
 }
 
 impl<I> StreamUgoiraDataProvider<I>
 where
     I: Send,
 {
-    pub fn into_stream(self) -> BoxStream<'static, (I, Result<Vec<UgoiraFrame>>)> {
+    pub fn into_stream(self) -> BoxStream<'static, (I, Result<Ugoira>)> {
+        let spool_dir = self.spool_dir;
+        let limit = self.limit;
         Box::pin(
             self.illust_ids
-                .par_then_unordered(None, |(i, illust_id)| async move {
-                    let prov = UgoiraDataProvider::new(illust_id);
-                    let data = prov.into().await;
-                    (i, data)
+                .par_then_unordered(self.params, move |(i, illust_id)| {
+                    let prov = UgoiraDataProvider::new(illust_id, spool_dir.clone());
+                    let limit = limit.clone();
+                    async move {
+                        let _permit = limit.acquire().await;
+                        let data = prov.into().await;
+                        (i, data)
+                    }
+                }),
+        )
+    }
+}
+
+#[derive(Constructor)]
+pub struct StreamLocalUgoiraDataProvider<I>
+where
+    I: 'static,
+{
+    paths: BoxStream<'static, (I, PathBuf)>,
+    limit: JobLimit,
+    params: ParParams,
+}
+
+impl<I> StreamLocalUgoiraDataProvider<I>
+where
+    I: Send,
+{
+    pub fn into_stream(self) -> BoxStream<'static, (I, Result<Ugoira>)> {
+        let limit = self.limit;
+        Box::pin(
+            self.paths
+                .par_then_unordered(self.params, move |(i, path)| {
+                    let limit = limit.clone();
+                    async move {
+                        let _permit = limit.acquire().await;
+                        let prov = LocalUgoiraDataProvider::new(path);
+                        let data = prov.into().await;
+                        (i, data)
+                    }
                 }),
         )
     }
@@ -1 +1,20 @@
+use std::sync::Arc;
 
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+
+/// Limit of jobs running at once, shared by the stages that count against the same option
+#[derive(Clone)]
+pub struct JobLimit(Arc<Semaphore>);
+
+impl JobLimit {
+    pub fn new(jobs: usize) -> Self {
+        Self(Arc::new(Semaphore::new(jobs)))
+    }
+
+    /// Waits for a free job slot, taken until the permit is dropped
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        // The semaphore is never closed
+        Arc::clone(&self.0).acquire_owned().await.unwrap()
+    }
+}
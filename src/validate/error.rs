@@ -0,0 +1,74 @@
+use std::fmt;
+
+/// Problem found in an ugoira archive
+#[derive(Debug)]
+pub enum Problem {
+    /// Frame listed in the metadata is not in the archive
+    Missing {
+        file: String,
+    },
+    Empty {
+        file: String,
+    },
+    Corrupt {
+        file: String,
+        reason: String,
+    },
+    Dimensions {
+        file: String,
+        width: u32,
+        height: u32,
+        expected_width: u32,
+        expected_height: u32,
+    },
+    /// Archive entry that the metadata doesn't list
+    Unlisted {
+        file: String,
+    },
+}
+
+impl Problem {
+    /// Whether the ugoira can still be encoded as is, so the problem is only a warning
+    pub fn is_harmless(&self) -> bool {
+        matches!(self, Problem::Unlisted { .. })
+    }
+}
+
+#[derive(Debug)]
+pub struct Error {
+    pub problems: Vec<Problem>,
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Missing { file } => write!(f, "frame {file} is missing from the archive"),
+            Problem::Empty { file } => write!(f, "frame {file} is empty"),
+            Problem::Corrupt { file, reason } => write!(f, "frame {file} is corrupt: {reason}"),
+            Problem::Dimensions {
+                file,
+                width,
+                height,
+                expected_width,
+                expected_height,
+            } => write!(
+                f,
+                "frame {file} is {width}x{height}, expected {expected_width}x{expected_height}"
+            ),
+            Problem::Unlisted { file } => write!(f, "archive entry {file} is not a listed frame"),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let problems = self
+            .problems
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        write!(f, "invalid ugoira: {}", problems.join("; "))
+    }
+}
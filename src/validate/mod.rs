@@ -0,0 +1,483 @@
+mod error;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::Cursor;
+
+use anyhow::Result;
+use bytes::Bytes;
+use derive_more::Constructor;
+use futures::stream::BoxStream;
+use image::imageops::FilterType;
+use image::DynamicImage;
+use image::GenericImageView;
+use image::ImageFormat;
+use image::ImageOutputFormat;
+use par_stream::ParParams;
+use par_stream::ParStreamExt;
+
+pub use self::error::Error;
+pub use self::error::Problem;
+use crate::pixiv::types::UgoiraDelay;
+use crate::ugoira::Ugoira;
+use crate::ugoira::UgoiraFrame;
+use crate::ugoira::ANIMATION_JSON;
+
+/// JPEG quality of the frames re-encoded after resizing
+const REPAIR_JPEG_QUALITY: u8 = 95;
+
+/// Checks every frame of the ugoira before it's encoded.
+///
+/// Unlisted entries are only reported. With `repair`, unreadable frames are dropped
+/// and their delay is given to a neighbour, and frames of odd dimensions are resized.
+#[derive(Constructor)]
+struct UgoiraValidator {
+    ugoira: Ugoira,
+    repair: bool,
+}
+
+/// Ugoira that passed validation, along with the problems that were repaired or ignored
+pub type Validated = (Ugoira, Vec<Problem>);
+
+/// Outcome of checking a single frame
+enum FrameCheck {
+    Ok {
+        width: u32,
+        height: u32,
+        /// Decoded frame, kept when it's likely to be resized
+        image: Option<DynamicImage>,
+    },
+    Unreadable(Problem),
+}
+
+impl UgoiraValidator {
+    async fn into(self) -> Result<Validated> {
+        tokio::task::spawn_blocking(move || self.validate()).await?
+    }
+
+    fn validate(self) -> Result<Validated> {
+        let mut ugoira = self.ugoira;
+        let mut problems = Vec::new();
+
+        let entries = ugoira.archive.file_names();
+        let listed = ugoira
+            .frames
+            .iter()
+            .map(|frame| frame.file.as_str())
+            .collect::<HashSet<_>>();
+        for entry in &entries {
+            if !listed.contains(entry.as_str()) && entry != ANIMATION_JSON && !entry.ends_with('/')
+            {
+                problems.push(Problem::Unlisted {
+                    file: entry.clone(),
+                });
+            }
+        }
+
+        let entries = entries.iter().map(String::as_str).collect::<HashSet<_>>();
+        // Frames that differ from the first readable one are kept decoded for resizing,
+        // so that usually nothing is decoded twice
+        let mut first = None;
+        let checks = ugoira
+            .frames
+            .iter()
+            .map(|frame| {
+                let mut check = check_frame(frame, entries.contains(frame.file.as_str()));
+                if let FrameCheck::Ok {
+                    width,
+                    height,
+                    image,
+                } = &mut check
+                {
+                    if !self.repair || *first.get_or_insert((*width, *height)) == (*width, *height)
+                    {
+                        *image = None;
+                    }
+                }
+                check
+            })
+            .collect::<Vec<_>>();
+
+        // Frames are expected to be of the most common size
+        let mut sizes = HashMap::<(u32, u32), usize>::new();
+        for check in &checks {
+            if let FrameCheck::Ok { width, height, .. } = check {
+                *sizes.entry((*width, *height)).or_default() += 1;
+            }
+        }
+        let expected = checks.iter().find_map(|check| match check {
+            FrameCheck::Ok { width, height, .. } => {
+                let count = sizes[&(*width, *height)];
+                sizes
+                    .values()
+                    .all(|other| *other <= count)
+                    .then_some((*width, *height))
+            }
+            FrameCheck::Unreadable(_) => None,
+        });
+
+        let mut resize = (0..checks.len()).map(|_| None).collect::<Vec<_>>();
+        let mut unreadable = vec![false; checks.len()];
+        for (i, (frame, check)) in ugoira.frames.iter().zip(checks).enumerate() {
+            match check {
+                FrameCheck::Ok {
+                    width,
+                    height,
+                    image,
+                } => {
+                    let (expected_width, expected_height) = expected.unwrap();
+                    if (width, height) != (expected_width, expected_height) {
+                        resize[i] = Some(image);
+                        problems.push(Problem::Dimensions {
+                            file: frame.file.clone(),
+                            width,
+                            height,
+                            expected_width,
+                            expected_height,
+                        });
+                    }
+                }
+                FrameCheck::Unreadable(problem) => {
+                    unreadable[i] = true;
+                    problems.push(problem);
+                }
+            }
+        }
+
+        if problems.iter().all(Problem::is_harmless) {
+            return Ok((ugoira, problems));
+        }
+        let expected = match expected {
+            Some(expected) if self.repair => expected,
+            _ => return Err(Error { problems }.into()),
+        };
+
+        let frames = std::mem::take(&mut ugoira.frames);
+        let mut repaired = Vec::<UgoiraFrame>::with_capacity(frames.len());
+        // Delay of dropped frames before the first readable one
+        let mut carried = UgoiraDelay::from(0);
+        for ((frame, resize), unreadable) in frames.into_iter().zip(resize).zip(unreadable) {
+            if unreadable {
+                match repaired.last_mut() {
+                    Some(last) => last.delay = last.delay + frame.delay,
+                    None => carried = carried + frame.delay,
+                }
+                continue;
+            }
+
+            let mut frame = match resize {
+                Some(image) => {
+                    let data = resize_frame(&frame, image, expected)?;
+                    UgoiraFrame::repaired(frame.file, frame.delay, data)
+                }
+                None => frame,
+            };
+            frame.delay = frame.delay + std::mem::replace(&mut carried, UgoiraDelay::from(0));
+            repaired.push(frame);
+        }
+        ugoira.frames = repaired;
+
+        Ok((ugoira, problems))
+    }
+}
+
+fn check_frame(frame: &UgoiraFrame, in_archive: bool) -> FrameCheck {
+    let file = frame.file.clone();
+    if !in_archive {
+        return FrameCheck::Unreadable(Problem::Missing { file });
+    }
+
+    let data = match frame.blocking_data() {
+        Ok(data) if data.is_empty() => return FrameCheck::Unreadable(Problem::Empty { file }),
+        Ok(data) => data,
+        Err(e) => {
+            let reason = e.to_string();
+            return FrameCheck::Unreadable(Problem::Corrupt { file, reason });
+        }
+    };
+
+    // Decoding the whole image catches truncated data that the header alone doesn't show
+    match image::load_from_memory(&data) {
+        Ok(image) => {
+            let (width, height) = image.dimensions();
+            let image = Some(image);
+            FrameCheck::Ok {
+                width,
+                height,
+                image,
+            }
+        }
+        Err(e) => {
+            let reason = e.to_string();
+            FrameCheck::Unreadable(Problem::Corrupt { file, reason })
+        }
+    }
+}
+
+/// Resizes the frame, decoding it again unless already decoded,
+/// and keeps the format its file name says it's in
+fn resize_frame(
+    frame: &UgoiraFrame,
+    image: Option<DynamicImage>,
+    (width, height): (u32, u32),
+) -> Result<Bytes> {
+    let image = match image {
+        Some(image) => image,
+        None => image::load_from_memory(&frame.blocking_data()?)?,
+    };
+    let image = image.resize_exact(width, height, FilterType::Lanczos3);
+
+    let mut buf_writer = Cursor::new(Vec::<u8>::new());
+    match ImageFormat::from_path(&frame.file) {
+        Ok(ImageFormat::Png) => image.write_to(&mut buf_writer, ImageOutputFormat::Png)?,
+        _ => DynamicImage::ImageRgb8(image.to_rgb8()).write_to(
+            &mut buf_writer,
+            ImageOutputFormat::Jpeg(REPAIR_JPEG_QUALITY),
+        )?,
+    }
+
+    Ok(Bytes::from(buf_writer.into_inner()))
+}
+
+#[derive(Constructor)]
+pub struct StreamUgoiraValidator<I>
+where
+    I: 'static,
+{
+    data_stream: BoxStream<'static, (I, Ugoira)>,
+    repair: bool,
+    params: ParParams,
+}
+
+impl<I> StreamUgoiraValidator<I>
+where
+    I: Send,
+{
+    pub fn into_stream(self) -> BoxStream<'static, (I, Result<Validated>)> {
+        let repair = self.repair;
+        Box::pin(
+            self.data_stream
+                .par_then_unordered(self.params, move |(i, ugoira)| async move {
+                    let validator = UgoiraValidator::new(ugoira, repair);
+                    let validate = validator.into().await;
+                    (i, validate)
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::io::Write;
+    use std::path::Path;
+
+    use image::ImageOutputFormat;
+    use image::RgbImage;
+    use tempfile::TempDir;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use super::Error;
+    use super::Problem;
+    use super::UgoiraValidator;
+    use super::Validated;
+    use crate::pixiv::types::UgoiraDelay;
+    use crate::pixiv::types::UgoiraMetaFrame;
+    use crate::ugoira::Ugoira;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut buf_writer = Cursor::new(Vec::new());
+        RgbImage::new(width, height)
+            .write_to(&mut buf_writer, ImageOutputFormat::Png)
+            .unwrap();
+        buf_writer.into_inner()
+    }
+
+    /// Validates a zip of the entries, listing the frames with their delays
+    async fn validate(
+        entries: &[(&str, Vec<u8>)],
+        frames: &[(&str, u32)],
+        repair: bool,
+    ) -> anyhow::Result<Validated> {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("12345_ugoira600x600.zip");
+        write_zip(&path, entries);
+
+        let frames = frames
+            .iter()
+            .map(|(file, delay)| UgoiraMetaFrame {
+                file: file.to_string(),
+                delay: UgoiraDelay::from(*delay),
+            })
+            .collect();
+        let ugoira = Ugoira::with_frames(path, frames).await.unwrap();
+        UgoiraValidator::new(ugoira, repair).into().await
+    }
+
+    fn write_zip(path: &Path, entries: &[(&str, Vec<u8>)]) {
+        let mut zip_writer = ZipWriter::new(std::fs::File::create(path).unwrap());
+        for (name, data) in entries {
+            if name.ends_with('/') {
+                zip_writer
+                    .add_directory(*name, FileOptions::default())
+                    .unwrap();
+                continue;
+            }
+            zip_writer
+                .start_file(*name, FileOptions::default())
+                .unwrap();
+            zip_writer.write_all(data).unwrap();
+        }
+        zip_writer.finish().unwrap();
+    }
+
+    fn frame_list(validated: &Validated) -> Vec<(&str, u32)> {
+        validated
+            .0
+            .frames
+            .iter()
+            .map(|frame| (frame.file.as_str(), u32::from(frame.delay)))
+            .collect()
+    }
+
+    /// One frame of every kind of problem, and an entry that isn't a frame
+    fn broken_entries() -> Vec<(&'static str, Vec<u8>)> {
+        let mut corrupt = png(32, 24);
+        corrupt.truncate(corrupt.len() / 2);
+
+        vec![
+            ("000000.png", png(32, 24)),
+            ("000001.png", Vec::new()),
+            ("000002.png", png(30, 20)),
+            ("000003.png", corrupt),
+            ("000004.png", png(32, 24)),
+            ("000005.png", png(32, 24)),
+            ("thumbs.db", b"not a frame".to_vec()),
+        ]
+    }
+
+    const BROKEN_FRAMES: &[(&str, u32)] = &[
+        ("000000.png", 100),
+        ("000001.png", 100),
+        ("000002.png", 100),
+        ("000003.png", 100),
+        ("000004.png", 100),
+        ("000005.png", 50),
+    ];
+
+    #[tokio::test]
+    async fn repairs_broken_frames() {
+        let validated = validate(&broken_entries(), BROKEN_FRAMES, true)
+            .await
+            .unwrap();
+
+        let problems = &validated.1;
+        assert_eq!(problems.len(), 4, "{problems:?}");
+        assert!(matches!(&problems[0], Problem::Unlisted { file } if file == "thumbs.db"));
+        assert!(matches!(&problems[1], Problem::Empty { file } if file == "000001.png"));
+        assert!(matches!(
+            &problems[2],
+            Problem::Dimensions { file, width: 30, height: 20, expected_width: 32, expected_height: 24 }
+                if file == "000002.png"
+        ));
+        assert!(matches!(&problems[3], Problem::Corrupt { file, .. } if file == "000003.png"));
+
+        // Dropped frames extend the ones before them, so the length stays the same
+        let frames = frame_list(&validated);
+        assert_eq!(
+            frames,
+            [
+                ("000000.png", 200),
+                ("000002.png", 200),
+                ("000004.png", 100),
+                ("000005.png", 50),
+            ]
+        );
+        assert_eq!(frames.iter().map(|(_, delay)| delay).sum::<u32>(), 550);
+
+        let resized = validated.0.frames[1].blocking_data().unwrap();
+        let resized = image::load_from_memory(&resized).unwrap();
+        assert_eq!((resized.width(), resized.height()), (32, 24));
+    }
+
+    #[tokio::test]
+    async fn fails_without_repair() {
+        let e = validate(&broken_entries(), BROKEN_FRAMES, false)
+            .await
+            .unwrap_err();
+
+        let problems = &e.downcast_ref::<Error>().unwrap().problems;
+        assert_eq!(problems.len(), 4, "{problems:?}");
+        assert_eq!(problems.iter().filter(|p| !p.is_harmless()).count(), 3);
+    }
+
+    #[tokio::test]
+    async fn carries_delay_of_leading_dropped_frames() {
+        let entries = [
+            ("000000.png", Vec::new()),
+            ("000001.png", png(32, 24)),
+            ("000002.png", png(32, 24)),
+        ];
+        let frames = [
+            ("000000.png", 100),
+            ("000001.png", 50),
+            ("000002.png", 70),
+            ("000003.png", 30),
+        ];
+
+        let validated = validate(&entries, &frames, true).await.unwrap();
+        assert!(matches!(&validated.1[0], Problem::Empty { .. }));
+        assert!(matches!(&validated.1[1], Problem::Missing { file } if file == "000003.png"));
+        // The leading frame's delay is carried to the next one, the trailing one's to the last
+        assert_eq!(
+            frame_list(&validated),
+            [("000001.png", 150), ("000002.png", 100)]
+        );
+    }
+
+    #[tokio::test]
+    async fn expects_the_most_common_size() {
+        // The first frame is the odd one out
+        let entries = [
+            ("000000.png", png(30, 20)),
+            ("000001.png", png(32, 24)),
+            ("000002.png", png(32, 24)),
+        ];
+        let frames = [
+            ("000000.png", 100),
+            ("000001.png", 100),
+            ("000002.png", 100),
+        ];
+
+        let validated = validate(&entries, &frames, true).await.unwrap();
+        assert_eq!(validated.1.len(), 1);
+        assert!(matches!(
+            &validated.1[0],
+            Problem::Dimensions { file, expected_width: 32, expected_height: 24, .. }
+                if file == "000000.png"
+        ));
+
+        let resized = validated.0.frames[0].blocking_data().unwrap();
+        let resized = image::load_from_memory(&resized).unwrap();
+        assert_eq!((resized.width(), resized.height()), (32, 24));
+    }
+
+    #[tokio::test]
+    async fn only_warns_about_unlisted_entries() {
+        let entries = [
+            ("000000.png", png(32, 24)),
+            ("000001.png", png(32, 24)),
+            ("animation.json", b"{}".to_vec()),
+            ("extra/", Vec::new()),
+            ("extra.txt", b"hello".to_vec()),
+        ];
+        let frames = [("000000.png", 100), ("000001.png", 100)];
+
+        let validated = validate(&entries, &frames, false).await.unwrap();
+        assert_eq!(validated.1.len(), 1);
+        assert!(validated.1[0].is_harmless());
+        assert!(matches!(&validated.1[0], Problem::Unlisted { file } if file == "extra.txt"));
+        assert_eq!(frame_list(&validated), frames);
+    }
+}